@@ -1,5 +1,4 @@
-use std::fs::File;
-use std::io::{self, BufReader, Read, Write};
+use std::io::{self, BufRead, BufReader, Read, Write};
 use bitvec::prelude::*;
 
 const MAX_WRITER_BITCAP : usize = 512 * 8;
@@ -10,18 +9,47 @@ pub struct BinaryWriter<T : Write> {
     bytes_written: usize
 }
 
-pub struct BinaryReader {
-    buf_reader: BufReader<File>,
-    bit_buf: usize,
-    bits_read: u8,
+pub struct BinaryReader<R : Read> {
+    buf_reader: BufReader<R>,
+    bits: BitBuf,
+    /// bytes moved from buf_reader to bits
+    bytes_loaded: usize,
+}
+
+/// Bits that were read from the source but not consumed yet, the next one is the highest of `buf`.
+/// Hot loops take a copy out of the BinaryReader (see BinaryReader::bits),
+/// so it can stay in registers, and put it back when they are done.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct BitBuf {
+    buf: usize,
+    pub(crate) left: u8,
+}
+
+impl BitBuf {
+    /// The next `count` bits, they have to be in the buffer (see `left`)
+    #[inline]
+    pub(crate) fn peek(&self, count: u8) -> usize {
+        debug_assert!(count > 0 && count <= self.left);
+        self.buf >> (BIT_BUF_BITS - count as usize)
+    }
+
+    /// Skips `count` bits, they have to be in the buffer (see `left`)
+    #[inline]
+    pub(crate) fn consume(&mut self, count: u8) {
+        debug_assert!(count <= MAX_READ_BITS && count <= self.left);
+        self.buf <<= count;
+        self.left -= count;
+    }
 }
 
 const MAX_BIT_BUF_BYTES: usize = std::mem::size_of::<usize>();
+const BIT_BUF_BITS: usize = MAX_BIT_BUF_BYTES * 8;
+/// After read_buf at least this many bits are in the buffer, unless the end is reached
+pub const MAX_READ_BITS: u8 = (BIT_BUF_BITS - 7) as u8;
 const HIGH_DEBUG: bool = false;
 
 impl<T : Write> BinaryWriter<T> {
     pub fn new(w: T) -> Self {
-        dbg!(MAX_BIT_BUF_BYTES);
         BinaryWriter {
             writer: w,
             bit_buf: BitVec::with_capacity(MAX_WRITER_BITCAP),
@@ -33,6 +61,11 @@ impl<T : Write> BinaryWriter<T> {
         self.bytes_written
     }
 
+    /// Bits written so far, including the ones that are still buffered
+    pub fn get_bits_written(&self) -> usize {
+        self.bytes_written * 8 + self.bit_buf.len()
+    }
+
     pub fn write_buf(&mut self) -> io::Result<()> {
         // write bytes that are "ready", copy last "not ready" byte to new bit_buf
 
@@ -44,7 +77,7 @@ impl<T : Write> BinaryWriter<T> {
         if bits_rem == 0 {
             let slice = self.bit_buf.as_slice();
             // can write all
-            self.writer.write_all(&slice)?;
+            self.writer.write_all(slice)?;
             self.bit_buf.clear();
         }
         else {
@@ -61,6 +94,17 @@ impl<T : Write> BinaryWriter<T> {
         Ok(())
     }
 
+    /// Pads the last byte with 0 bits and writes everything out.
+    /// Without this the last bits that don't fill a whole byte would get lost.
+    pub fn finish(&mut self) -> io::Result<()> {
+        while !self.bit_buf.len().is_multiple_of(8) {
+            self.bit_buf.push(false);
+        }
+
+        self.write_buf()?;
+        self.writer.flush()
+    }
+
     pub fn write_bit(&mut self, b: bool) -> io::Result<()> {
         // write bit
 
@@ -73,6 +117,18 @@ impl<T : Write> BinaryWriter<T> {
         Ok(())
     }
 
+    /// Writes the lowest `count` bits of `value`, the highest of them first
+    pub fn write_bits(&mut self, value: usize, count: u8) -> io::Result<()> {
+        for i in (0..count).rev() {
+            self.bit_buf.push((value >> i) & 1 == 1);
+        }
+
+        if self.bit_buf.len() > MAX_WRITER_BITCAP {
+            self.write_buf()?;
+        }
+        Ok(())
+    }
+
     pub fn write_byte(&mut self, b: u8) -> io::Result<()> {
         self.bit_buf.extend(b.as_bitslice::<BigEndian>());
 
@@ -95,88 +151,152 @@ impl<T : Write> BinaryWriter<T> {
     }
 }
 
-impl BinaryReader {
-    pub fn new(f: File) -> Self {
+impl<R : Read> BinaryReader<R> {
+    pub fn new(r: R) -> Self {
         BinaryReader {
-            buf_reader: BufReader::new(f),
-            bit_buf: 0,
-            bits_read: MAX_BIT_BUF_BYTES as u8 * 8, // force read_buf when first read
+            buf_reader: BufReader::new(r),
+            bits: BitBuf { buf: 0, left: 0 }, // force read_buf when first read
+            bytes_loaded: 0,
         }
     }
 
-    pub fn read_buf(&mut self) -> io::Result<()> {
-        let mut tbuf = [0u8; MAX_BIT_BUF_BYTES];
-
-        let read_bytes = self.buf_reader.read(&mut tbuf)?;
+    /// Bits read (consumed) so far
+    pub fn get_bits_read(&self) -> usize {
+        self.bytes_loaded * 8 - self.bits.left as usize
+    }
 
-        //println!("{}", read_bytes);
-        if read_bytes == 0 {
-            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Reached end"));
+    /// Fills bit_buf with as many whole bytes as fit in.
+    /// The source may return less bytes than requested, so this reads byte for byte until
+    /// the buffer is full or the end of the source is reached.
+    pub fn read_buf(&mut self) -> io::Result<()> {
+        while self.bits.left as usize <= BIT_BUF_BITS - 8 {
+            let buf = match self.buf_reader.fill_buf() {
+                Ok([]) => break,
+                Ok(buf) => buf,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+
+            let wanted = (BIT_BUF_BITS - self.bits.left as usize) / 8;
+            let len = std::cmp::min(wanted, buf.len());
+
+            if buf.len() >= MAX_BIT_BUF_BYTES {
+                // fast path: load all bytes at once and only keep the wanted ones
+                let mut tbuf = [0u8; MAX_BIT_BUF_BYTES];
+                tbuf[..len].copy_from_slice(&buf[..len]);
+                self.bits.buf |= usize::from_be_bytes(tbuf) >> self.bits.left;
+                self.bits.left += len as u8 * 8;
+            } else {
+                for &byte in &buf[..len] {
+                    self.bits.buf |= (byte as usize) << (BIT_BUF_BITS - 8 - self.bits.left as usize);
+                    self.bits.left += 8;
+                }
+            }
+
+            self.buf_reader.consume(len);
+            self.bytes_loaded += len;
         }
 
-        //TODO: read less than sizeof<usize> bytes
-        //println!("{:?}", tbuf);
-        let val = usize::from_be_bytes(tbuf);
-
         if cfg!(debug_assertions) && HIGH_DEBUG {
-            println!("read buf {:#066b}", val);
+            println!("read buf {:#066b}", self.bits.buf);
         }
 
-        self.bit_buf = val;
-        self.bits_read = 0;
-
         Ok(())
     }
 
-    pub fn read_bit(&mut self) -> io::Result<bool> {
-        if self.bits_read >= MAX_BIT_BUF_BYTES as u8 * 8 {
+    /// Returns the next `count` bits (at most MAX_READ_BITS) without reading them,
+    /// bits after the end of the source are 0.
+    #[inline]
+    pub fn peek_bits(&mut self, count: u8) -> io::Result<usize> {
+        debug_assert!(count <= MAX_READ_BITS, "Can't read more than {} bits at once", MAX_READ_BITS);
+
+        if count == 0 {
+            return Ok(0);
+        }
+
+        if self.bits.left < count {
             self.read_buf()?;
         }
-        let res = Ok((self.bit_buf >> (MAX_BIT_BUF_BYTES * 8 - 1)) != 0);
 
-        self.bit_buf <<= 1;
-        self.bits_read += 1;
-        res
+        Ok(self.bits.buf >> (BIT_BUF_BITS - count as usize))
     }
 
-    pub fn read_byte(&mut self) -> io::Result<u8> {
-        if self.bits_read >= MAX_BIT_BUF_BYTES as u8 * 8 {
-            self.read_buf()?;
+    /// Skips `count` bits, they need to be peeked before.
+    #[inline]
+    pub fn consume_bits(&mut self, count: u8) -> io::Result<()> {
+        if self.bits.left < count {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Reached end"));
         }
-        if self.bits_read <= MAX_BIT_BUF_BYTES as u8 * 8 - 8 {
-            let res = Ok((self.bit_buf >> (MAX_BIT_BUF_BYTES * 8 - 8)) as u8);
-            //println!("{:#034b}", self.bit_buf);
-            self.bits_read += 8;
-            self.bit_buf <<= 8;
 
-            res
+        self.bits.consume(count);
+        Ok(())
+    }
 
-        } else {
-            // problem: part of this byte is stored in next chunk
-            let remaining = 8 - (MAX_BIT_BUF_BYTES as u8 * 8 - self.bits_read);
+    /// A copy of the bits in the buffer, it has to be given back with set_bits before the reader is used again
+    #[inline]
+    pub(crate) fn bits(&self) -> BitBuf {
+        self.bits
+    }
 
-            assert!(remaining <= 8);
+    #[inline]
+    pub(crate) fn set_bits(&mut self, bits: BitBuf) {
+        self.bits = bits;
+    }
 
-            let mut res = (self.bit_buf >> (MAX_BIT_BUF_BYTES * 8 - 8)) as u8; // stores the high bits at the right place;
+    /// Fills the buffer if less than MAX_READ_BITS bits are left in it
+    #[inline]
+    pub(crate) fn refill(&mut self) -> io::Result<()> {
+        if self.bits.left < MAX_READ_BITS {
             self.read_buf()?;
+        }
+        Ok(())
+    }
+
+    /// Fills `bits` (taken out with `bits`) from the bytes that are buffered already, without reading from the source.
+    /// Returns false if less than a whole bit buffer of bytes is buffered, read_buf is needed then.
+    #[inline]
+    pub(crate) fn refill_buffered(&mut self, bits: &mut BitBuf) -> bool {
+        let bytes = match self.buf_reader.buffer().get(..MAX_BIT_BUF_BYTES) {
+            Some(bytes) => bytes,
+            None => return false,
+        };
 
-            res |= (self.bit_buf >> (MAX_BIT_BUF_BYTES as u8 * 8 - remaining)) as u8;
+        let len = (BIT_BUF_BITS - bits.left as usize) / 8;
+        let mut tbuf = [0u8; MAX_BIT_BUF_BYTES];
+        tbuf.copy_from_slice(bytes);
+
+        // the bits of the byte that doesn't fit completely are the next ones of the source,
+        // so they can stay in the buffer, reading that byte later sets the same bits.
+        // A full buffer can't be shifted by all of its bits, nothing is added then
+        bits.buf |= usize::from_be_bytes(tbuf).checked_shr(bits.left as u32).unwrap_or(0);
+        bits.left += len as u8 * 8;
+        self.buf_reader.consume(len);
+        self.bytes_loaded += len;
+        true
+    }
 
-            self.bit_buf <<= remaining;
-            self.bits_read += remaining;
+    /// Reads `count` bits (at most MAX_READ_BITS) and returns them in the lowest bits of the result,
+    /// the first bit read is the highest.
+    pub fn read_bits(&mut self, count: u8) -> io::Result<usize> {
+        let res = self.peek_bits(count)?;
+        self.consume_bits(count)?;
 
-            Ok(res)
-        }
+        Ok(res)
+    }
+
+    pub fn read_bit(&mut self) -> io::Result<bool> {
+        Ok(self.read_bits(1)? != 0)
+    }
+
+    pub fn read_byte(&mut self) -> io::Result<u8> {
+        Ok(self.read_bits(8)? as u8)
     }
 }
 
 impl<T : Write> Drop for BinaryWriter<T> {
     fn drop(&mut self) {
-        println!("Writing remaining bits...");
-        
-
-        self.write_buf().unwrap();
-        self.writer.flush().unwrap();
+        // errors can't be returned here, call finish before to get them
+        let _ = self.finish();
     }
 }
 
@@ -197,9 +317,9 @@ fn binary_io_test() -> Result<(), io::Error> {
     // content: true 1 false 2 true 3 false 4 true 5 false 6 true 7 false 8 true 9 false
 
     {
-        let mut reader: BinaryReader = BinaryReader::new(std::fs::File::open("./test.bin")?);
+        let mut reader: BinaryReader<std::fs::File> = BinaryReader::new(std::fs::File::open("./test.bin")?);
 
-        assert_eq!(reader.read_bit()?, true);
+        assert!(reader.read_bit()?);
 
         println!("reading loop");
         for i in 1..10 {
@@ -210,3 +330,35 @@ fn binary_io_test() -> Result<(), io::Error> {
 
     Ok(())
 }
+
+#[test]
+fn binary_io_short_reads() -> Result<(), io::Error> {
+    /// Returns at most one byte per read, like a slow socket would
+    struct OneByteReader<'a>(&'a [u8]);
+
+    impl<'a> Read for OneByteReader<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match (self.0.split_first(), buf.first_mut()) {
+                (Some((b, rest)), Some(out)) => {
+                    *out = *b;
+                    self.0 = rest;
+                    Ok(1)
+                }
+                _ => Ok(0),
+            }
+        }
+    }
+
+    let data = [0b1010_1100, 0xff, 0x00, 0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde, 0xf0];
+    let mut reader = BinaryReader::new(OneByteReader(&data));
+
+    assert_eq!(reader.read_bits(3)?, 0b101);
+    assert_eq!(reader.read_bits(9)?, 0b0_1100_1111);
+    assert_eq!(reader.read_byte()?, 0xf0);
+    assert_eq!(reader.read_bits(4)?, 0);
+    assert_eq!(reader.read_bits(MAX_READ_BITS)?, (0x12_3456_789a_bcde << 1) | 1);
+    assert_eq!(reader.read_bits(7)?, 0b111_0000);
+    assert_eq!(reader.read_bit().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+    Ok(())
+}
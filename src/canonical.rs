@@ -0,0 +1,214 @@
+use crate::binary_io::{BinaryReader, BinaryWriter};
+use crate::error::{HuffmanError, Result};
+use crate::{Node, SYMBOL_COUNT};
+use std::io::{self, Read, Write};
+
+/// Codes are calculated in an u64, so they can't be longer
+pub const MAX_CODE_LEN: u8 = 64;
+
+/// Returns the code length of each symbol in the tree, 0 if the symbol isn't used.
+/// If the root is a leaf, its symbol gets the length 1, so it still needs a bit that the decoder can read.
+pub fn code_lengths(root: &Node) -> Vec<u8> {
+    fn add_lengths(lengths: &mut Vec<u8>, node: &Node, depth: usize) {
+        match node {
+            Node::Branch(_, l, r) => {
+                add_lengths(lengths, l, depth + 1);
+                add_lengths(lengths, r, depth + 1);
+            }
+            Node::Leaf(_, symbol) => {
+                lengths[*symbol as usize] = std::cmp::min(std::cmp::max(depth, 1), u8::MAX as usize) as u8;
+            }
+        }
+    }
+
+    let mut lengths = vec![0; SYMBOL_COUNT];
+    add_lengths(&mut lengths, root, 0);
+    lengths
+}
+
+/// Assigns the canonical codes: shorter codes come first, codes of the same length are ordered by symbol,
+/// and each code is the previous one plus 1 (shifted left if it gets longer).
+/// Returns (symbol, code, length) in that order. A single used symbol gets the code 0 with 1 bit,
+/// the other code of that length stays unused.
+pub fn canonical_codes(lengths: &[u8]) -> Result<Vec<(u16, u64, u8)>> {
+    let mut symbols: Vec<(u8, u16)> = lengths
+        .iter()
+        .enumerate()
+        .filter(|(_, &len)| len > 0)
+        .map(|(symbol, &len)| (len, symbol as u16))
+        .collect();
+    symbols.sort_unstable();
+
+    match symbols.len() {
+        0 => return Err(HuffmanError::CorruptTree("No symbol has a code")),
+        1 if symbols[0].0 == 1 => return Ok(vec![(symbols[0].1, 0, 1)]),
+        1 => return Err(HuffmanError::CorruptTree("A single symbol needs a code of length 1")),
+        _ => {}
+    }
+
+    let mut codes = Vec::with_capacity(symbols.len());
+    let mut code: u64 = 0;
+    let mut prev_len = symbols[0].0;
+
+    for (len, symbol) in symbols {
+        if len > MAX_CODE_LEN {
+            return Err(HuffmanError::CorruptTree("Code is too long"));
+        }
+
+        code <<= len - prev_len;
+        prev_len = len;
+
+        // the code needs to fit in len bits, otherwise too many short codes were used
+        if len < 64 && code >> len != 0 {
+            return Err(HuffmanError::CorruptTree("Code lengths don't form a tree"));
+        }
+
+        codes.push((symbol, code, len));
+        code = code.wrapping_add(1);
+    }
+
+    // after the last code all codes of that length need to be used up, otherwise the tree has holes
+    if prev_len < 64 && code != 1 << prev_len || prev_len == 64 && code != 0 {
+        return Err(HuffmanError::CorruptTree("Code lengths don't form a complete tree"));
+    }
+
+    Ok(codes)
+}
+
+/// Builds the tree of the canonical codes, the counts of the nodes are 0.
+pub fn tree_from_lengths(lengths: &[u8]) -> Result<Node> {
+    /// `codes` are sorted and all start with the same `depth` bits
+    fn build(codes: &[(u16, u64, u8)], depth: u8) -> Node {
+        if codes.len() == 1 {
+            return Node::Leaf(0, codes[0].0);
+        }
+
+        // canonical codes are sorted, so all codes with a 0 at this depth come first
+        let split = codes
+            .iter()
+            .position(|(_, code, len)| (code >> (len - depth - 1)) & 1 == 1)
+            .unwrap_or(codes.len());
+
+        Node::Branch(
+            0,
+            Box::new(build(&codes[..split], depth + 1)),
+            Box::new(build(&codes[split..], depth + 1)),
+        )
+    }
+
+    Ok(build(&canonical_codes(lengths)?, 0))
+}
+
+/// Code lengths get written for all symbols in order, each relative to the previous length (starting at 0):
+///
+/// 0                    same length as the previous symbol
+/// 10 + sign + 3 bits   previous length + or - (1 to 8)
+/// 110 + 5 bits         1 to 32 unused symbols, the previous length stays the same
+/// 111 + 7 bits         the length itself
+pub fn write_code_lengths<W: Write>(lengths: &[u8], out: &mut BinaryWriter<W>) -> io::Result<()> {
+    for (value, bits) in length_items(lengths) {
+        out.write_bits(value, bits)?;
+    }
+
+    Ok(())
+}
+
+/// Number of bits `write_code_lengths` needs for `lengths`
+pub fn code_lengths_bits(lengths: &[u8]) -> usize {
+    length_items(lengths).iter().map(|&(_, bits)| bits as usize).sum()
+}
+
+/// The items of `write_code_lengths` as (value, number of bits)
+fn length_items(lengths: &[u8]) -> Vec<(usize, u8)> {
+    let mut items = Vec::new();
+    let mut prev: u8 = 0;
+    let mut i = 0;
+
+    while i < lengths.len() {
+        let len = lengths[i];
+
+        if len == 0 {
+            let run = lengths[i..].iter().take(32).take_while(|&&l| l == 0).count();
+            items.push(((0b110 << 5) | (run - 1), 8));
+            i += run;
+            continue;
+        }
+
+        if len == prev {
+            items.push((0, 1));
+        } else if (len as i16 - prev as i16).abs() <= 8 {
+            let sign = (len < prev) as usize;
+            let diff = (len as i16 - prev as i16).unsigned_abs() as usize - 1;
+            items.push(((0b10 << 4) | (sign << 3) | diff, 6));
+        } else {
+            items.push(((0b111 << 7) | len as usize, 10));
+        }
+
+        prev = len;
+        i += 1;
+    }
+
+    items
+}
+
+/// Reads the code lengths written by `write_code_lengths`, lengths above `max_code_len` are invalid.
+pub fn read_code_lengths<R: Read>(reader: &mut BinaryReader<R>, max_code_len: u8) -> Result<Vec<u8>> {
+    let mut lengths = Vec::with_capacity(SYMBOL_COUNT);
+    let mut prev: u8 = 0;
+
+    while lengths.len() < SYMBOL_COUNT {
+        if !reader.read_bit()? {
+            lengths.push(prev);
+        } else if !reader.read_bit()? {
+            let negative = reader.read_bit()?;
+            let diff = reader.read_bits(3)? as u8 + 1;
+
+            prev = if negative {
+                prev.checked_sub(diff).filter(|&l| l > 0)
+            } else {
+                prev.checked_add(diff).filter(|&l| l <= max_code_len)
+            }
+            .ok_or(HuffmanError::CorruptTree("Code length out of range"))?;
+
+            lengths.push(prev);
+        } else if !reader.read_bit()? {
+            let run = reader.read_bits(5)? + 1;
+
+            if lengths.len() + run > SYMBOL_COUNT {
+                return Err(HuffmanError::CorruptTree("Too many code lengths"));
+            }
+
+            lengths.resize(lengths.len() + run, 0);
+        } else {
+            prev = reader.read_bits(7)? as u8;
+
+            if prev == 0 || prev > max_code_len {
+                return Err(HuffmanError::CorruptTree("Code length out of range"));
+            }
+
+            lengths.push(prev);
+        }
+    }
+
+    Ok(lengths)
+}
+
+#[test]
+fn canonical_tree() -> Result<()> {
+    // a: 0, b: 10, c: 110, d: 111
+    let lengths = [1, 2, 3, 3];
+
+    let codes = canonical_codes(&lengths)?;
+    assert_eq!(codes, vec![(0, 0b0, 1), (1, 0b10, 2), (2, 0b110, 3), (3, 0b111, 3)]);
+
+    let root = tree_from_lengths(&lengths)?;
+    let mut restored = code_lengths(&root);
+    restored.truncate(lengths.len());
+    assert_eq!(restored, lengths);
+
+    // holes and too many codes are rejected
+    assert!(canonical_codes(&[1, 2, 3]).is_err());
+    assert!(canonical_codes(&[1, 1, 2]).is_err());
+
+    Ok(())
+}
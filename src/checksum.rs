@@ -0,0 +1,124 @@
+use crate::error::{HuffmanError, Result};
+
+/// Tables for the CRC32 (IEEE) polynomial, one entry for each byte value.
+/// `CRC_TABLES[0]` is the usual table, `CRC_TABLES[n]` gives the CRC of a byte followed by n zero bytes,
+/// so 8 bytes can be handled at once (slicing-by-8).
+const CRC_TABLES: [[u32; 256]; 8] = crc_tables();
+
+const fn crc_tables() -> [[u32; 256]; 8] {
+    let mut tables = [[0u32; 256]; 8];
+    let mut i = 0;
+
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+            bit += 1;
+        }
+
+        tables[0][i] = crc;
+        i += 1;
+    }
+
+    let mut n = 1;
+    while n < 8 {
+        let mut i = 0;
+        while i < 256 {
+            let prev = tables[n - 1][i];
+            tables[n][i] = (prev >> 8) ^ tables[0][(prev & 0xFF) as usize];
+            i += 1;
+        }
+        n += 1;
+    }
+
+    tables
+}
+
+/// CRC32 that can be calculated over data that arrives in parts
+#[derive(Clone, Copy, Debug)]
+pub struct Crc32 {
+    crc: u32,
+}
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Crc32 { crc: 0xFFFF_FFFF }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        let t = &CRC_TABLES;
+        let mut crc = self.crc;
+        let mut chunks = data.chunks_exact(8);
+
+        for chunk in &mut chunks {
+            let low = crc ^ u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+            let high = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]);
+
+            crc = t[7][(low & 0xFF) as usize]
+                ^ t[6][((low >> 8) & 0xFF) as usize]
+                ^ t[5][((low >> 16) & 0xFF) as usize]
+                ^ t[4][(low >> 24) as usize]
+                ^ t[3][(high & 0xFF) as usize]
+                ^ t[2][((high >> 8) & 0xFF) as usize]
+                ^ t[1][((high >> 16) & 0xFF) as usize]
+                ^ t[0][(high >> 24) as usize];
+        }
+
+        for &byte in chunks.remainder() {
+            crc = t[0][((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+        }
+
+        self.crc = crc;
+    }
+
+    /// The checksum of all data so far
+    pub fn value(&self) -> u32 {
+        !self.crc
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Crc32::new()
+    }
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.value()
+}
+
+/// Returns a `ChecksumMismatch` error if the checksums are different
+pub fn check(expected: u32, actual: u32) -> Result<()> {
+    if expected != actual {
+        return Err(HuffmanError::ChecksumMismatch { expected, actual });
+    }
+
+    Ok(())
+}
+
+#[test]
+fn crc32_check_value() {
+    assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    assert_eq!(crc32(b""), 0);
+
+    let mut crc = Crc32::new();
+    crc.update(b"1234");
+    crc.update(b"56789");
+    assert_eq!(crc.value(), 0xCBF4_3926);
+
+    // the 8 byte steps give the same as one byte at a time, for every offset
+    let data: Vec<u8> = (0..100u32).map(|i| (i * 7 + i / 3) as u8).collect();
+    for split in 0..data.len() {
+        let mut crc = Crc32::new();
+        crc.update(&data[..split]);
+        crc.update(&data[split..]);
+
+        let mut bytewise = Crc32::new();
+        data.iter().for_each(|byte| bytewise.update(std::slice::from_ref(byte)));
+        assert_eq!(crc.value(), bytewise.value());
+    }
+}
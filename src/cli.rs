@@ -0,0 +1,370 @@
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub const USAGE: &str = "\
+Usage: huffman compress|decompress|test|info [OPTIONS] [INPUT|-]...
+
+Without INPUT or with -, the input is read from stdin and written to stdout.
+Without a command, the mode is guessed from the .huff suffix of each INPUT.
+Input files are removed once they were compressed or decompressed, unless -k or -c is given.
+The name, modification time and permissions of the input are stored and restored when decompressing.
+test decodes each INPUT without writing anything and prints OK or FAILED for it.
+info shows the header, the sizes and the codes of each INPUT without decoding it.
+
+Options:
+  -o, --output OUT   write to OUT (- for stdout) instead of INPUT.huff / INPUT without .huff, only for one INPUT
+  -c, --stdout       write to stdout and keep the input files
+  -k, --keep         keep the input files
+  -f, --force        overwrite existing output files
+  -r, --recursive    compress or decompress the files in directories
+  -n, --no-name      don't store the name, time and permissions, or don't restore them
+  -e, --encode       same as the compress command
+  -d, --decode       same as the decompress command
+  -t, --test         same as the test command
+  -l, --list         same as the info command
+      --json         print info as JSON, one line for each INPUT
+  -h, --help         print this help";
+
+/// Short options that don't take a value, they can be combined like `-kf`
+const SHORT_FLAGS: &str = "ckfrnedtlh";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    Compress,
+    Decompress,
+    /// Decompress without writing the output
+    Test,
+    /// Show what is in the header and the tree
+    Info,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Input {
+    Stdin,
+    File(PathBuf),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Output {
+    Stdout,
+    File(PathBuf),
+    /// The output is thrown away
+    Null,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Args {
+    /// None if it should be guessed from the file name
+    pub mode: Option<Mode>,
+    pub inputs: Vec<Input>,
+    /// Set by -o or -c, otherwise the name is derived from each input
+    pub output: Option<Output>,
+    pub keep: bool,
+    pub force: bool,
+    pub recursive: bool,
+    /// Don't store or restore the name and metadata of the file
+    pub no_name: bool,
+    /// Print info as JSON
+    pub json: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Run(Args),
+    Help,
+}
+
+/// One input with its mode and output
+#[derive(Debug, PartialEq)]
+pub struct Job {
+    pub mode: Mode,
+    pub input: Input,
+    pub output: Output,
+}
+
+/// Parses the arguments without the program name, the error is the message for the user
+pub fn parse<I: IntoIterator<Item = OsString>>(args: I) -> Result<Command, String> {
+    let mut parsed = Args {
+        mode: None,
+        inputs: Vec::new(),
+        output: None,
+        keep: false,
+        force: false,
+        recursive: false,
+        no_name: false,
+        json: false,
+    };
+    let mut output = None;
+    let mut to_stdout = false;
+    // the command has to come before the inputs
+    let mut positional = 0;
+
+    let mut args = split_short_flags(args).into_iter();
+    while let Some(arg) = args.next() {
+        let is_positional = arg.to_str().is_none_or(|a| !a.starts_with('-') || a == "-");
+
+        match arg.to_str() {
+            Some("-h") | Some("--help") => return Ok(Command::Help),
+            Some("-o") | Some("--output") => match args.next() {
+                Some(out) => output = Some(out),
+                None => return Err(format!("{} needs a path", arg.to_string_lossy())),
+            },
+            Some("-c") | Some("--stdout") => to_stdout = true,
+            Some("-k") | Some("--keep") => parsed.keep = true,
+            Some("-f") | Some("--force") => parsed.force = true,
+            Some("-r") | Some("--recursive") => parsed.recursive = true,
+            Some("-n") | Some("--no-name") => parsed.no_name = true,
+            Some("-e") | Some("--encode") => set_mode(&mut parsed.mode, Mode::Compress)?,
+            Some("-d") | Some("--decode") => set_mode(&mut parsed.mode, Mode::Decompress)?,
+            Some("-t") | Some("--test") => set_mode(&mut parsed.mode, Mode::Test)?,
+            Some("-l") | Some("--list") => set_mode(&mut parsed.mode, Mode::Info)?,
+            Some("--json") => parsed.json = true,
+            Some("compress") if positional == 0 => set_mode(&mut parsed.mode, Mode::Compress)?,
+            Some("decompress") if positional == 0 => set_mode(&mut parsed.mode, Mode::Decompress)?,
+            Some("test") if positional == 0 => set_mode(&mut parsed.mode, Mode::Test)?,
+            Some("info") if positional == 0 => set_mode(&mut parsed.mode, Mode::Info)?,
+            Some(option) if option.starts_with('-') && option != "-" => return Err(format!("Unknown option {}", option)),
+            Some("-") if parsed.inputs.contains(&Input::Stdin) => return Err("stdin can only be read once".to_owned()),
+            Some("-") => parsed.inputs.push(Input::Stdin),
+            _ => parsed.inputs.push(Input::File(PathBuf::from(arg))),
+        }
+
+        if is_positional {
+            positional += 1;
+        }
+    }
+
+    if parsed.inputs.is_empty() {
+        parsed.inputs.push(Input::Stdin);
+    }
+
+    parsed.output = match output {
+        _ if to_stdout => Some(Output::Stdout),
+        Some(out) if out == "-" => Some(Output::Stdout),
+        Some(_) if parsed.inputs.len() > 1 || parsed.recursive => return Err("-o can only be used with one input file".to_owned()),
+        Some(out) => Some(Output::File(PathBuf::from(out))),
+        None => None,
+    };
+
+    if matches!(parsed.mode, Some(Mode::Test) | Some(Mode::Info)) && parsed.output.is_some() {
+        return Err("test and info don't write an output, -o and -c can't be used with them".to_owned());
+    }
+
+    // compressed files written after each other can't be told apart anymore
+    let many_inputs = parsed.inputs.len() > 1 || parsed.recursive;
+    if parsed.output == Some(Output::Stdout) && parsed.mode != Some(Mode::Decompress) && many_inputs {
+        return Err("Only one input can be compressed to stdout".to_owned());
+    }
+
+    Ok(Command::Run(parsed))
+}
+
+/// `-kf` becomes `-k -f`
+fn split_short_flags<I: IntoIterator<Item = OsString>>(args: I) -> Vec<OsString> {
+    let mut split = Vec::new();
+
+    for arg in args {
+        match arg.to_str() {
+            Some(a) if a.len() > 2 && a.starts_with('-') && !a.starts_with("--") && a[1..].chars().all(|c| SHORT_FLAGS.contains(c)) => {
+                split.extend(a[1..].chars().map(|c| OsString::from(format!("-{}", c))));
+            }
+            _ => split.push(arg),
+        }
+    }
+
+    split
+}
+
+fn set_mode(mode: &mut Option<Mode>, new: Mode) -> Result<(), String> {
+    match mode.replace(new) {
+        Some(old) if old != new => Err("Only one of compress, decompress, test and info can be used".to_owned()),
+        _ => Ok(()),
+    }
+}
+
+fn has_huff_suffix(path: &Path) -> bool {
+    path.extension().is_some_and(|e| e == "huff")
+}
+
+/// `INPUT.huff` when compressing, INPUT without `.huff` when decompressing
+pub fn output_path(input: &Path, mode: Mode) -> Result<PathBuf, String> {
+    match mode {
+        Mode::Compress => {
+            let mut name = input.as_os_str().to_owned();
+            name.push(".huff");
+            Ok(PathBuf::from(name))
+        }
+        Mode::Decompress if has_huff_suffix(input) => Ok(input.with_extension("")),
+        Mode::Decompress => Err("has no .huff suffix, use -o to name the output".to_owned()),
+        Mode::Test | Mode::Info => Err("test and info have no output".to_owned()),
+    }
+}
+
+/// Replaces directories with the files in them if `recursive` is set, sorted by name.
+/// Compressing skips `.huff` files, the other modes only take them. Symlinks in directories are skipped.
+/// Directories that can't be used are returned as errors with their message.
+pub fn expand_inputs(inputs: &[Input], mode: Option<Mode>, recursive: bool) -> Vec<Result<Input, (PathBuf, String)>> {
+    fn walk(dir: &Path, mode: Mode, files: &mut Vec<PathBuf>) -> io::Result<()> {
+        let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<fs::DirEntry>>>()?;
+        entries.sort_by_key(|e| e.file_name());
+
+        for entry in entries {
+            let file_type = entry.file_type()?;
+            let path = entry.path();
+
+            if file_type.is_dir() {
+                walk(&path, mode, files)?;
+            } else if file_type.is_file() && has_huff_suffix(&path) == (mode != Mode::Compress) {
+                files.push(path);
+            }
+        }
+
+        Ok(())
+    }
+
+    let mut expanded = Vec::new();
+
+    for input in inputs {
+        let dir = match input {
+            Input::File(path) if path.is_dir() => path,
+            _ => {
+                expanded.push(Ok(input.clone()));
+                continue;
+            }
+        };
+
+        let mode = match mode {
+            Some(mode) if recursive => mode,
+            Some(_) => {
+                expanded.push(Err((dir.clone(), "is a directory, use -r for the files in it".to_owned())));
+                continue;
+            }
+            None => {
+                expanded.push(Err((dir.clone(), "is a directory, compress or decompress is needed for it".to_owned())));
+                continue;
+            }
+        };
+
+        let mut files = Vec::new();
+        match walk(dir, mode, &mut files) {
+            Ok(()) => expanded.extend(files.into_iter().map(|f| Ok(Input::File(f)))),
+            Err(e) => expanded.push(Err((dir.clone(), e.to_string()))),
+        }
+    }
+
+    expanded
+}
+
+impl Args {
+    /// The mode and output for `input`
+    pub fn job(&self, input: Input) -> Result<Job, String> {
+        if let Some(mode @ Mode::Test) | Some(mode @ Mode::Info) = self.mode {
+            return Ok(Job {
+                mode,
+                input,
+                output: Output::Null,
+            });
+        }
+
+        let mode = match (self.mode, &input) {
+            (Some(mode), _) => mode,
+            (None, Input::File(path)) if has_huff_suffix(path) => Mode::Decompress,
+            (None, Input::File(_)) => Mode::Compress,
+            (None, Input::Stdin) => return Err("compress or decompress is needed when reading from stdin".to_owned()),
+        };
+
+        let output = match (&self.output, &input) {
+            (Some(output), _) => output.clone(),
+            (None, Input::Stdin) => Output::Stdout,
+            (None, Input::File(path)) => Output::File(output_path(path, mode)?),
+        };
+
+        Ok(Job { mode, input, output })
+    }
+}
+
+#[test]
+fn parse_args() {
+    fn parse_strs(args: &[&str]) -> Result<Command, String> {
+        parse(args.iter().map(OsString::from))
+    }
+
+    fn job(args: &[&str]) -> Result<Job, String> {
+        match parse_strs(args)? {
+            Command::Run(args) => args.job(args.inputs[0].clone()),
+            Command::Help => Err("help".to_owned()),
+        }
+    }
+
+    let run = |mode, input: Input, output: Output| Ok(Job { mode, input, output });
+    let file = |name: &str| PathBuf::from(name);
+
+    assert_eq!(
+        job(&["compress", "bible.txt"]),
+        run(Mode::Compress, Input::File(file("bible.txt")), Output::File(file("bible.txt.huff")))
+    );
+    assert_eq!(
+        job(&["decompress", "-o", "out.txt", "bible.txt.huff"]),
+        run(Mode::Decompress, Input::File(file("bible.txt.huff")), Output::File(file("out.txt")))
+    );
+    assert_eq!(job(&["decompress"]), run(Mode::Decompress, Input::Stdin, Output::Stdout));
+    assert_eq!(
+        job(&["compress", "-", "-o", "x.huff"]),
+        run(Mode::Compress, Input::Stdin, Output::File(file("x.huff")))
+    );
+    assert_eq!(
+        job(&["-c", "bible.txt.huff"]),
+        run(Mode::Decompress, Input::File(file("bible.txt.huff")), Output::Stdout)
+    );
+    // a file called like a command
+    assert_eq!(
+        job(&["compress", "compress"]),
+        run(Mode::Compress, Input::File(file("compress")), Output::File(file("compress.huff")))
+    );
+
+    match parse_strs(&["decompress", "-kfrn", "a.huff", "dir"]) {
+        Ok(Command::Run(args)) => {
+            assert!(args.keep && args.force && args.recursive && args.no_name);
+            assert_eq!(args.inputs, vec![Input::File(file("a.huff")), Input::File(file("dir"))]);
+        }
+        other => panic!("{:?}", other),
+    }
+
+    assert_eq!(job(&["test", "a.txt"]), run(Mode::Test, Input::File(file("a.txt")), Output::Null));
+    assert_eq!(job(&["-t"]), run(Mode::Test, Input::Stdin, Output::Null));
+    assert!(parse_strs(&["test", "-o", "out", "a.huff"]).is_err());
+    assert_eq!(job(&["info", "--json", "a.huff"]), run(Mode::Info, Input::File(file("a.huff")), Output::Null));
+    assert!(parse_strs(&["info", "-c", "a.huff"]).is_err());
+
+    assert_eq!(parse_strs(&["--help"]), Ok(Command::Help));
+    assert!(job(&["decompress", "bible.txt"]).is_err());
+    assert!(parse_strs(&["compress", "-x"]).is_err());
+    assert!(parse_strs(&["compress", "-kx"]).is_err());
+    assert!(parse_strs(&["compress", "-o"]).is_err());
+    assert!(parse_strs(&["compress", "-o", "out", "a", "b"]).is_err());
+    assert!(parse_strs(&["compress", "-c", "a", "b"]).is_err());
+    assert!(parse_strs(&["decompress", "-c", "a.huff", "b.huff"]).is_ok());
+    assert!(parse_strs(&["compress", "-", "-"]).is_err());
+    assert!(parse_strs(&["-e", "-d", "a"]).is_err());
+    assert!(job(&[]).is_err());
+}
+
+#[test]
+fn expand_directories() -> io::Result<()> {
+    let dir = std::env::temp_dir().join(format!("huffman_expand_{}", std::process::id()));
+    fs::create_dir_all(dir.join("sub"))?;
+    for name in &["b.txt", "a.txt.huff", "sub/c.txt"] {
+        fs::write(dir.join(name), b"data")?;
+    }
+
+    let inputs = vec![Input::File(dir.clone())];
+    let files = |mode| expand_inputs(&inputs, Some(mode), true).into_iter().collect::<Result<Vec<Input>, _>>();
+
+    assert_eq!(files(Mode::Compress), Ok(vec![Input::File(dir.join("b.txt")), Input::File(dir.join("sub/c.txt"))]));
+    assert_eq!(files(Mode::Decompress), Ok(vec![Input::File(dir.join("a.txt.huff"))]));
+    assert!(expand_inputs(&inputs, Some(Mode::Compress), false)[0].is_err());
+
+    fs::remove_dir_all(&dir)
+}
@@ -1,92 +1,738 @@
 use crate::binary_io::BinaryReader;
-use crate::{Node, HEADER};
-use std::fs::File;
-use std::io::{self, BufWriter, Read, Write};
-use std::path::PathBuf;
+use crate::canonical::{read_code_lengths, tree_from_lengths};
+use crate::checksum::{self, crc32, Crc32};
+use crate::error::{HuffmanError, Result};
+use crate::header::{Header, FLAG_BLOCK_INDEX, FLAG_CHECKSUMS};
+use crate::pool;
+use crate::table::DecodeTable;
+use crate::{Node, EOF_SYMBOL, MAX_BUF_SIZE, MAX_WORKERS};
+use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Arc;
 
+/// The 0.1 format ended the data with this byte
+pub(crate) const LEGACY_EOF: u16 = 0x1c;
+
+/// A tree of the 0.1 format can't have more leaves than symbols
+const MAX_LEGACY_LEAVES: usize = crate::SYMBOL_COUNT;
+/// Deepest leaf a tree with MAX_LEGACY_LEAVES leaves can have
+const MAX_LEGACY_DEPTH: usize = MAX_LEGACY_LEAVES - 1;
+
+/// Reads the tree of the 0.1 format, in preorder:
 /// branch: 0
-/// leaf:   1
-fn construct_tree(reader: &mut BinaryReader) -> io::Result<Node> {
-    let is_leaf = reader.read_bit()?;
-    //dbg!(&is_leaf);
-    if is_leaf {
-        let value = reader.read_byte()?;
-
-        Ok(Node::Leaf(0, value))
-    } else {
-        let left = construct_tree(reader)?;
-        let right = construct_tree(reader)?;
-
-        Ok(Node::Branch(0, Box::new(left), Box::new(right)))
-    }
-}
-
-fn traverse_tree(reader: &mut BinaryReader, node: &Node) -> io::Result<u8> {
-    match node {
-        Node::Branch(_, l, r) => {
-            let go_right = reader.read_bit()?;
-            if go_right {
-                traverse_tree(reader, r)
-            } else {
-                traverse_tree(reader, l)
+/// leaf:   1 + 8 bit symbol
+///
+/// The branches that still miss a child are kept on a stack instead of recursing,
+/// so a crafted file can't overflow the stack. Trees that are too deep, have too many leaves
+/// or the same symbol twice are rejected.
+fn construct_tree<R: Read>(reader: &mut BinaryReader<R>) -> Result<Node> {
+    // the left child of each open branch, once it's known
+    let mut open: Vec<Option<Node>> = Vec::new();
+    let mut seen = [false; 256];
+    let mut leaves = 0;
+
+    // the file ending inside of the tree means it is incomplete
+    let incomplete = |e: io::Error| match e.kind() {
+        io::ErrorKind::UnexpectedEof => HuffmanError::CorruptTree("Tree is incomplete"),
+        _ => HuffmanError::Io(e),
+    };
+
+    loop {
+        if !reader.read_bit().map_err(incomplete)? {
+            if open.len() >= MAX_LEGACY_DEPTH {
+                return Err(HuffmanError::CorruptTree("Tree is too deep"));
+            }
+            open.push(None);
+            continue;
+        }
+
+        let value = reader.read_byte().map_err(incomplete)?;
+
+        leaves += 1;
+        if leaves > MAX_LEGACY_LEAVES {
+            return Err(HuffmanError::CorruptTree("Tree has too many leaves"));
+        }
+        if std::mem::replace(&mut seen[value as usize], true) {
+            return Err(HuffmanError::CorruptTree("Symbol appears twice in the tree"));
+        }
+
+        // the finished node completes all branches that only waited for their right child
+        let mut node = Node::Leaf(0, value as u16);
+        loop {
+            match open.pop() {
+                None => return Ok(node),
+                Some(None) => {
+                    open.push(Some(node));
+                    break;
+                }
+                Some(Some(left)) => node = Node::Branch(0, Box::new(left), Box::new(node)),
             }
         }
-        Node::Leaf(_, v) => Ok(*v),
     }
 }
 
-pub fn decode(path: PathBuf) -> io::Result<()> {
-    let mut file = std::fs::File::open(&path)?;
+/// Decodes a huffman stream from any reader, files of the 0.1 format can be read as well.
+/// The header and the first code lengths are read when the Decoder is created.
+pub struct Decoder<R: Read> {
+    reader: BinaryReader<R>,
+    header: Header,
+    root: Node,
+    table: DecodeTable,
+    block_crc: Crc32,
+    stream_crc: Crc32,
+    bytes_decoded: u64,
+    finished: bool,
+}
 
-    // check if header is correct
-    let h_len = HEADER.len();
-    let mut h_buf = vec![0; h_len];
+impl<R: Read> Decoder<R> {
+    pub fn new(mut input: R) -> Result<Self> {
+        let header = Header::read(&mut input)?;
+        Decoder::with_header(header, input)
+    }
 
-    file.read_exact(&mut h_buf[..])?;
+    /// Continues after the header was already read from `input`
+    fn with_header(header: Header, input: R) -> Result<Self> {
+        let mut reader = BinaryReader::new(input);
 
-    // check if header is valid
-    if HEADER != &h_buf[..] {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            "Header of file is not valid",
-        ));
+        let root: Node = if header.is_legacy() {
+            construct_tree(&mut reader)?
+        } else {
+            // there is no previous table the first block could use
+            if !reader.read_bit()? {
+                return Err(HuffmanError::CorruptBlock("First block has no code lengths"));
+            }
+
+            tree_from_lengths(&read_code_lengths(&mut reader, header.max_code_len)?)?
+        };
+        let table = if header.is_legacy() {
+            DecodeTable::with_eof(&root, LEGACY_EOF)
+        } else {
+            DecodeTable::new(&root)
+        };
+
+        Ok(Decoder {
+            reader,
+            header,
+            root,
+            table,
+            block_crc: Crc32::new(),
+            stream_crc: Crc32::new(),
+            bytes_decoded: 0,
+            finished: false,
+        })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    pub fn tree(&self) -> &Node {
+        &self.root
     }
 
-    let mut reader = BinaryReader::new(file);
+    /// Decodes up to `max_len` bytes and appends them to `buf`.
+    /// Less bytes are only decoded when the end of the stream is reached.
+    /// The checksums are checked at the end of each block and the stream.
+    fn decode_chunk(&mut self, buf: &mut Vec<u8>, max_len: usize) -> Result<()> {
+        // start of the bytes that aren't in the checksums yet
+        let mut unchecked = buf.len();
+        let end = buf.len() + max_len;
+        let legacy = self.header.is_legacy();
+        let checksums = self.header.has_flag(FLAG_CHECKSUMS);
+
+        while buf.len() < end && !self.finished {
+            let val = match self.table.decode_bytes(&mut self.reader, buf, end - buf.len()) {
+                Ok(None) => break,
+                Ok(Some(val)) => val,
+                // the 0.1 encoder could lose the last bits, so the data also ends with the file
+                Err(ref e) if legacy && e.kind() == io::ErrorKind::UnexpectedEof => {
+                    self.finished = true;
+                    break;
+                }
+                Err(e) => return Err(e.into()),
+            };
 
-    let root: Node = construct_tree(&mut reader)?;
-    let mut path_new = path.clone();
-    // get file name
+            if legacy {
+                // the table decodes LEGACY_EOF as EOF_SYMBOL, the tree of the 0.1 format has no other symbols
+                self.finished = true;
+                break;
+            }
 
-    match path.extension() {
-        Some(e) => {
-            if e.to_str().unwrap().ends_with(".huff") {
-                let mut new_ext = e.to_str().unwrap().to_owned();
+            if val == EOF_SYMBOL {
+                self.add_decoded(&buf[unchecked..], checksums);
+                unchecked = buf.len();
 
-                new_ext.truncate(new_ext.len() - 5);
+                if checksums {
+                    checksum::check(self.reader.read_bits(32)? as u32, self.block_crc.value())?;
+                }
+                self.block_crc = Crc32::new();
 
-                path_new.set_extension(new_ext);
-            } else {
-                path_new.set_extension("txt");
+                // after each block one bit tells if another block follows,
+                // and its first bit if it has its own tree or uses the previous one
+                if !self.reader.read_bit()? {
+                    if checksums {
+                        checksum::check(self.reader.read_bits(32)? as u32, self.stream_crc.value())?;
+                    }
+                    check_size(&self.header, self.bytes_decoded)?;
+                    self.finished = true;
+                } else if self.reader.read_bit()? {
+                    self.root = tree_from_lengths(&read_code_lengths(&mut self.reader, self.header.max_code_len)?)?;
+                    self.table = DecodeTable::new(&self.root);
+                }
+                continue;
             }
+
+            return Err(HuffmanError::CorruptBlock("Unknown symbol"));
+        }
+
+        self.add_decoded(&buf[unchecked..], checksums);
+
+        Ok(())
+    }
+
+    /// Adds decoded bytes to the checksums and the size
+    fn add_decoded(&mut self, data: &[u8], checksums: bool) {
+        if checksums {
+            self.block_crc.update(data);
+            self.stream_crc.update(data);
         }
-        None => {
-            path_new.set_extension("txt");
+        self.bytes_decoded += data.len() as u64;
+    }
+
+    /// Writes the decoded bytes to `output`, returns the number of bytes written
+    pub fn decode<W: Write>(&mut self, mut output: W) -> Result<usize> {
+        let mut bytes_written = 0;
+        let mut buf = Vec::with_capacity(MAX_BUF_SIZE);
+
+        while !self.finished {
+            buf.clear();
+            self.decode_chunk(&mut buf, MAX_BUF_SIZE)?;
+
+            output.write_all(&buf)?;
+            bytes_written += buf.len();
+        }
+
+        output.flush()?;
+        Ok(bytes_written)
+    }
+}
+
+/// Decompresses while being read, so the data never needs to be on disk.
+pub struct HuffmanReader<R: Read> {
+    decoder: Decoder<R>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> HuffmanReader<R> {
+    /// Reads the header and the first code lengths
+    pub fn new(inner: R) -> Result<Self> {
+        Ok(HuffmanReader {
+            decoder: Decoder::new(inner)?,
+            buf: Vec::with_capacity(MAX_BUF_SIZE),
+            pos: 0,
+        })
+    }
+}
+
+impl<R: Read> Read for HuffmanReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let len = {
+            let available = self.fill_buf()?;
+            let len = std::cmp::min(available.len(), out.len());
+            out[..len].copy_from_slice(&available[..len]);
+            len
+        };
+
+        self.consume(len);
+        Ok(len)
+    }
+}
+
+impl<R: Read> BufRead for HuffmanReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos >= self.buf.len() {
+            self.buf.clear();
+            self.pos = 0;
+            self.decoder.decode_chunk(&mut self.buf, MAX_BUF_SIZE)?;
+        }
+
+        Ok(&self.buf[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = std::cmp::min(self.pos + amt, self.buf.len());
+    }
+}
+
+/// A block for the workers of `decode_seekable`, `data` holds the bytes of the block and
+/// the encoded symbols are between `start` and `end` (in bits from the start of `data`)
+struct BlockTask {
+    table: Arc<DecodeTable>,
+    data: Vec<u8>,
+    start: usize,
+    end: usize,
+}
+
+/// Whether the bit at `pos` of `data` is set
+fn bit(data: &[u8], pos: usize) -> bool {
+    data[pos / 8] & (0x80 >> (pos % 8)) != 0
+}
+
+/// Reads the block index at the end of `input`, the blocks start at byte `blocks_start`.
+/// Returns the offset and length of each block in bits and where the index starts (relative to `blocks_start`)
+pub(crate) fn read_index_at<R: Read + Seek>(input: &mut R, blocks_start: u64) -> Result<(Vec<(usize, usize)>, usize)> {
+    fn read_u64(bytes: &[u8]) -> usize {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(&bytes[..8]);
+        u64::from_be_bytes(buf) as usize
+    }
+
+    let data_len = input.seek(SeekFrom::End(0))?.saturating_sub(blocks_start) as usize;
+    if data_len < 8 {
+        return Err(HuffmanError::CorruptBlock("Block index is missing"));
+    }
+
+    let mut count = [0; 8];
+    input.seek(SeekFrom::End(-8))?;
+    input.read_exact(&mut count)?;
+
+    let count = read_u64(&count);
+    let index_len = count
+        .checked_mul(16)
+        .filter(|&len| count > 0 && len <= data_len - 8)
+        .ok_or(HuffmanError::CorruptBlock("Block index is not valid"))?;
+    let index_start = data_len - 8 - index_len;
+
+    let mut entries = vec![0; index_len];
+    input.seek(SeekFrom::Start(blocks_start + index_start as u64))?;
+    input.read_exact(&mut entries)?;
+
+    let mut index = Vec::with_capacity(count);
+    let mut prev_end = 0;
+
+    for entry in entries.chunks_exact(16) {
+        let (start, len) = (read_u64(&entry[..8]), read_u64(&entry[8..]));
+
+        // blocks are in order and can't overlap the index
+        match start.checked_add(len) {
+            Some(end) if start >= prev_end && len > 0 && end <= index_start * 8 => prev_end = end,
+            _ => return Err(HuffmanError::CorruptBlock("Block index is not valid")),
         }
+
+        index.push((start, len));
+    }
+
+    Ok((index, index_start))
+}
+
+/// Fails if the header has a size and it's not `size`
+fn check_size(header: &Header, size: u64) -> Result<()> {
+    match header.original_size {
+        Some(original_size) if original_size != size => Err(HuffmanError::CorruptBlock("Size of the data doesn't match the header")),
+        _ => Ok(()),
+    }
+}
+
+/// Decodes a whole stream that is in memory, the blocks get decoded by up to `thread_count` threads.
+/// Returns the number of bytes written.
+pub fn decode_parallel<W: Write>(data: &[u8], output: W, thread_count: usize) -> Result<usize> {
+    decode_seekable(io::Cursor::new(data), output, thread_count)
+}
+
+/// Decodes a stream with a block index by reading the index first and then one block after the other,
+/// the blocks get decoded by up to `thread_count` threads and the output is written in order.
+/// Only a few blocks are in memory at once. Streams without a block index (like the 0.1 format) are decoded by one thread.
+///
+/// The layout around the blocks is checked as well: the first block starts right after the header,
+/// a 1 bit comes before each following block and a 0 bit after the last one,
+/// then the stream checksum (if it has checksums) and only 0 bits until the index.
+/// Returns the number of bytes written.
+pub fn decode_seekable<R: Read + Seek, W: Write>(mut input: R, mut output: W, thread_count: usize) -> Result<usize> {
+    let header = Header::read(&mut input)?;
+
+    if !header.has_flag(FLAG_BLOCK_INDEX) {
+        return Decoder::with_header(header, input)?.decode(output);
     }
-    if cfg!(debug_assertions) {
-        println!("{:#?}", root);
+
+    let max_code_len = header.max_code_len;
+    let checksums = header.has_flag(FLAG_CHECKSUMS);
+    let blocks_start = input.stream_position()?;
+    let (index, index_start) = read_index_at(&mut input, blocks_start)?;
+
+    let (last_start, last_len) = index[index.len() - 1];
+    let stream_end = last_start + last_len;
+    if stream_end >= index_start * 8 {
+        return Err(HuffmanError::CorruptBlock("Stream doesn't end after the last block"));
     }
 
-    println!("Creating file @ {:?}", path_new);
-    let mut writer = BufWriter::new(File::create(path_new)?);
+    // the table of the block before is used by a block without own code lengths
+    let mut table: Option<Arc<DecodeTable>> = None;
+    let mut expected_start = 0;
+
+    let tasks = index.iter().map(|&(start, len)| -> Result<BlockTask> {
+        if start != expected_start {
+            return Err(HuffmanError::CorruptBlock("Blocks don't follow each other"));
+        }
+        expected_start = start + len + 1;
+
+        // the block and the bit after it, it's set if another block follows
+        let first_byte = start / 8;
+        let mut data = vec![0; (start + len + 1).div_ceil(8) - first_byte];
+        input.seek(SeekFrom::Start(blocks_start + first_byte as u64))?;
+        input.read_exact(&mut data)?;
+
+        let end = start + len - first_byte * 8;
+        if start + len != stream_end && !bit(&data, end) {
+            return Err(HuffmanError::CorruptBlock("Blocks don't follow each other"));
+        }
+
+        let mut reader = BinaryReader::new(&data[..]);
+        reader.read_bits((start % 8) as u8)?;
+
+        // the code lengths can't go past the end of the block
+        let too_long = |e| match e {
+            HuffmanError::UnexpectedEof => HuffmanError::CorruptBlock("Code lengths don't fit in the block"),
+            e => e,
+        };
+
+        if reader.read_bit()? {
+            let root = tree_from_lengths(&read_code_lengths(&mut reader, max_code_len).map_err(too_long)?)?;
+            table = Some(Arc::new(DecodeTable::new(&root)));
+        }
 
-    while let Ok(val) = traverse_tree(&mut reader, &root) {
-        if val == 0x1c {
-            break;
+        let data_start = reader.get_bits_read();
+        if data_start > end {
+            return Err(HuffmanError::CorruptBlock("Code lengths don't fit in the block"));
         }
-        writer.write_all(&[val])?;
+
+        Ok(BlockTask {
+            table: table.clone().ok_or(HuffmanError::CorruptBlock("First block has no code lengths"))?,
+            data,
+            start: data_start,
+            end,
+        })
+    });
+
+    let thread_count = thread_count.clamp(1, std::cmp::min(MAX_WORKERS, index.len()));
+    let mut bytes_written = 0;
+    let mut stream_crc = Crc32::new();
+
+    pool::ordered(thread_count, tasks, |task| decode_block(&task, checksums), |buf| {
+        output.write_all(&buf)?;
+        stream_crc.update(&buf);
+        bytes_written += buf.len();
+        Ok(())
+    })?;
+
+    // everything from the last byte of the last block to the index
+    let tail_start = stream_end / 8;
+    let mut tail = vec![0; index_start - tail_start];
+    input.seek(SeekFrom::Start(blocks_start + tail_start as u64))?;
+    input.read_exact(&mut tail)?;
+
+    // the bit after the last block ends the stream
+    let end = stream_end % 8;
+    if bit(&tail, end) {
+        return Err(HuffmanError::CorruptBlock("Stream doesn't end after the last block"));
+    }
+
+    let tail_end = end + 1 + if checksums { 32 } else { 0 };
+    if tail_end.div_ceil(8) != tail.len() || (tail_end..tail.len() * 8).any(|pos| bit(&tail, pos)) {
+        return Err(HuffmanError::CorruptBlock("Unexpected data after the last block"));
+    }
+
+    // the checksum of everything follows that bit
+    if checksums {
+        let mut reader = BinaryReader::new(&tail[..]);
+        reader.read_bits((end + 1) as u8)?;
+        checksum::check(reader.read_bits(32)? as u32, stream_crc.value())?;
     }
 
+    check_size(&header, bytes_written as u64)?;
+
+    output.flush()?;
+    Ok(bytes_written)
+}
+
+/// Decodes the data of one block and checks its checksum, the block has to end exactly at `task.end`
+fn decode_block(task: &BlockTask, checksums: bool) -> Result<Vec<u8>> {
+    let mut reader = BinaryReader::new(&task.data[task.start / 8..task.end.div_ceil(8)]);
+    reader.read_bits((task.start % 8) as u8)?;
+
+    let mut buf = Vec::with_capacity(MAX_BUF_SIZE);
+
+    loop {
+        match task.table.decode_bytes(&mut reader, &mut buf, MAX_BUF_SIZE)? {
+            Some(EOF_SYMBOL) => break,
+            Some(_) => return Err(HuffmanError::CorruptBlock("Unknown symbol")),
+            None => (),
+        }
+    }
+
+    if checksums {
+        checksum::check(reader.read_bits(32)? as u32, crc32(&buf))?;
+    }
+
+    if task.start - task.start % 8 + reader.get_bits_read() != task.end {
+        return Err(HuffmanError::CorruptBlock("Block doesn't end where the index says"));
+    }
+
+    Ok(buf)
+}
+
+/// Decompresses the content of a `.huff` file in memory.
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+
+    decode_parallel(data, &mut out, MAX_WORKERS)?;
+
+    Ok(out)
+}
+
+/// Decompresses the file at `path` to `output` and returns the number of bytes written.
+/// The blocks are decoded in parallel, they are read from the file when they are needed.
+pub fn decode_file<W: Write>(path: &Path, output: W) -> Result<usize> {
+    decode_seekable(std::fs::File::open(path)?, output, MAX_WORKERS)
+}
+
+#[test]
+fn compress_roundtrip() -> Result<()> {
+    let data = std::fs::read("./test.txt")?;
+
+    let compressed = crate::compress(&data);
+    assert!(compressed.starts_with(&crate::header::MAGIC));
+    assert_eq!(decompress(&compressed)?, data);
+
+    Ok(())
+}
+
+#[test]
+fn all_bytes_roundtrip() -> Result<()> {
+    // 0x1c used to be the end marker and cut off the data
+    let data: Vec<u8> = (0..=255u8).chain(vec![0x1c; 10]).chain(0..=255u8).collect();
+
+    assert_eq!(decompress(&crate::compress(&data))?, data);
+
+    Ok(())
+}
+
+#[test]
+fn empty_input_roundtrip() -> Result<()> {
+    // the only symbol is the EOF_SYMBOL, it gets a 1 bit code
+    let compressed = crate::compress(b"");
+    assert_eq!(decompress(&compressed)?, b"");
+
+    let mut out = Vec::new();
+    assert_eq!(Decoder::new(&compressed[..])?.decode(&mut out)?, 0);
+
+    let mut compressed = Vec::new();
+    crate::HuffmanWriter::new(&mut compressed)?.finish()?;
+    let mut decompressed = Vec::new();
+    HuffmanReader::new(&compressed[..])?.read_to_end(&mut decompressed)?;
+    assert!(decompressed.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn single_symbol_roundtrip() -> Result<()> {
+    for data in &[vec![b'a'], vec![b'a'; 1000], vec![0x1c; 1000]] {
+        assert_eq!(&decompress(&crate::compress(data))?, data);
+    }
+
+    // a tree of one leaf without the EOF_SYMBOL
+    let mut counter = [0; crate::SYMBOL_COUNT];
+    counter[b'a' as usize] = 10;
+    let mut encoder = crate::Encoder::new(crate::build_tree(&counter)?)?;
+    assert_eq!(crate::canonical::code_lengths(encoder.tree())[b'a' as usize], 1);
+
+    let mut out = Vec::new();
+    assert!(matches!(encoder.encode(&b"aaaa"[..], &mut out), Err(HuffmanError::MissingSymbol(EOF_SYMBOL))));
+
+    encoder = crate::Encoder::new(Node::Leaf(0, EOF_SYMBOL))?;
+    out.clear();
+    encoder.encode(&b""[..], &mut out)?;
+    assert_eq!(decompress(&out)?, b"");
+
+    Ok(())
+}
+
+#[test]
+fn huffman_reader_lines() -> io::Result<()> {
+    let data = std::fs::read_to_string("./test.txt")?;
+    let compressed = crate::compress(data.as_bytes());
+
+    let mut decompressed = String::new();
+    HuffmanReader::new(&compressed[..])?.read_to_string(&mut decompressed)?;
+    assert_eq!(decompressed, data);
+
+    let lines = HuffmanReader::new(&compressed[..])?.lines().collect::<io::Result<Vec<String>>>()?;
+    assert_eq!(lines, data.lines().collect::<Vec<&str>>());
+
+    Ok(())
+}
+
+#[test]
+fn chunk_sizes() -> Result<()> {
+    // doubling counts give codes of every length, some are longer than the fast table
+    let mut data = Vec::new();
+    for byte in 0..15u8 {
+        data.extend(std::iter::repeat_n(byte, 1 << byte));
+    }
+    let compressed = crate::compress(&data);
+
+    // chunks that end in the middle of a batch of lookups or of an entry of the fast table
+    for max_len in [1, 2, 3, 5, 11, 13, 1000] {
+        let mut decoder = Decoder::new(&compressed[..])?;
+        let mut out = Vec::new();
+
+        while !decoder.finished {
+            let before = out.len();
+            decoder.decode_chunk(&mut out, max_len)?;
+            assert!(out.len() - before <= max_len);
+        }
+        assert_eq!(out, data);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn parallel_blocks() -> Result<()> {
+    let data = std::fs::read("./test.txt")?;
+
+    let mut compressed = Vec::new();
+    {
+        let mut writer = crate::HuffmanWriter::with_block_size(&mut compressed, 100)?;
+        writer.write_all(&data)?;
+        writer.finish()?;
+    }
+
+    let mut out = Vec::new();
+    assert_eq!(decode_parallel(&compressed, &mut out, 4)?, data.len());
+    assert_eq!(out, data);
+
+    // a block count that doesn't fit in the file
+    let last = compressed.len() - 1;
+    compressed[last] = compressed[last].wrapping_add(1);
+    assert!(matches!(decompress(&compressed), Err(HuffmanError::CorruptBlock(_))));
+
+    Ok(())
+}
+
+#[test]
+fn short_blocks() -> Result<()> {
+    use crate::binary_io::BinaryWriter;
+
+    // one block that only has room for a part of its code lengths
+    let mut lengths = vec![0; crate::SYMBOL_COUNT];
+    lengths[b'a' as usize] = 1;
+    lengths[EOF_SYMBOL as usize] = 1;
+
+    let mut blocks = Vec::new();
+    let mut writer = BinaryWriter::new(&mut blocks);
+    writer.write_bit(true)?;
+    crate::canonical::write_code_lengths(&lengths, &mut writer)?;
+    let table_end = writer.get_bits_written();
+    writer.finish()?;
+    drop(writer);
+
+    // the block ends two bytes before its code lengths do, on a 0 bit so the layout is valid
+    let bit = |pos: usize| blocks[pos / 8] & (0x80 >> (pos % 8)) != 0;
+    let len = (table_end - 32..table_end - 16).rev().find(|&pos| !bit(pos)).expect("No 0 bit in the code lengths");
+    blocks.resize((len + 1 + 32).div_ceil(8), 0);
+
+    let mut data = Vec::new();
+    Header::new(crate::DEFAULT_MAX_CODE_LEN, None).write(&mut data)?;
+    data.extend_from_slice(&blocks);
+    data.extend_from_slice(&0u64.to_be_bytes());
+    data.extend_from_slice(&(len as u64).to_be_bytes());
+    data.extend_from_slice(&1u64.to_be_bytes());
+
+    assert!(matches!(decompress(&data), Err(HuffmanError::CorruptBlock(_))));
+
+    Ok(())
+}
+
+#[test]
+fn strict_layout() -> Result<()> {
+    let data = std::fs::read("./test.txt")?;
+    let compressed = crate::compress(&data);
+
+    let offset = crate::header::HEADER_LEN;
+    let (index, index_start) = read_index_at(&mut io::Cursor::new(&compressed), offset as u64)?;
+    let (start, len) = index[index.len() - 1];
+    let stream_end = start + len + 1 + 32;
+    assert!(stream_end % 8 != 0, "test needs padding bits");
+
+    // the bit that ends the stream, and a padding bit before the index
+    for &bit in &[start + len, index_start * 8 - 1] {
+        let mut corrupt = compressed.clone();
+        corrupt[offset + bit / 8] ^= 0x80 >> (bit % 8);
+        assert!(matches!(decompress(&corrupt), Err(HuffmanError::CorruptBlock(_))));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn checksum_mismatch() -> Result<()> {
+    let data = std::fs::read("./test.txt")?;
+    let mut compressed = crate::compress(&data);
+
+    // flip the last bit of the checksum of the last block
+    let offset = crate::header::HEADER_LEN;
+    let (index, _) = read_index_at(&mut io::Cursor::new(&compressed), offset as u64)?;
+    let (start, len) = index[index.len() - 1];
+    let bit = start + len - 1;
+    compressed[offset + bit / 8] ^= 0x80 >> (bit % 8);
+
+    assert!(matches!(decompress(&compressed), Err(HuffmanError::ChecksumMismatch { .. })));
+
+    let mut out = Vec::new();
+    assert!(matches!(Decoder::new(&compressed[..])?.decode(&mut out), Err(HuffmanError::ChecksumMismatch { .. })));
+
+    Ok(())
+}
+
+#[test]
+fn malicious_legacy_trees() -> Result<()> {
+    fn legacy_tree(bits: &[(usize, u8)]) -> io::Result<Vec<u8>> {
+        let mut data = crate::header::LEGACY_HEADER.to_vec();
+        let mut writer = crate::binary_io::BinaryWriter::new(&mut data);
+        for &(value, len) in bits {
+            writer.write_bits(value, len)?;
+        }
+        writer.finish()?;
+        drop(writer);
+        Ok(data)
+    }
+
+    // only branches, this used to overflow the stack
+    let mut data = crate::header::LEGACY_HEADER.to_vec();
+    data.resize(data.len() + (1 << 20), 0);
+    assert!(matches!(Decoder::new(&data[..]), Err(HuffmanError::CorruptTree(_))));
+
+    let duplicate = legacy_tree(&[(0, 1), (1, 1), (b'a' as usize, 8), (1, 1), (b'a' as usize, 8)])?;
+    assert!(matches!(Decoder::new(&duplicate[..]), Err(HuffmanError::CorruptTree(_))));
+
+    let incomplete = legacy_tree(&[(0, 1), (1, 1), (b'a' as usize, 8)])?;
+    assert!(matches!(Decoder::new(&incomplete[..]), Err(HuffmanError::CorruptTree(_))));
+
+    let valid = legacy_tree(&[(0, 1), (1, 1), (b'a' as usize, 8), (1, 1), (LEGACY_EOF as usize, 8), (0b001, 3)])?;
+    assert_eq!(decompress(&valid)?, b"aa");
+
+    Ok(())
+}
+
+#[test]
+fn legacy_files() -> Result<()> {
+    // written by the 0.1 encoder from test.txt with windows line endings
+    let decompressed = decompress(&std::fs::read("./test.txt.huff")?)?;
+    let decompressed: Vec<u8> = decompressed.into_iter().filter(|&b| b != b'\r').collect();
+
+    assert_eq!(decompressed, std::fs::read("./test.txt")?);
+
     Ok(())
 }
@@ -1,362 +1,811 @@
 use crate::binary_io::BinaryWriter;
-use crate::{Node, HEADER, MAX_BUF_SIZE};
+use crate::checksum::{crc32, Crc32};
+use crate::error::{HuffmanError, Result};
+use crate::frequency::FrequencyTable;
+use crate::canonical::{canonical_codes, code_lengths, code_lengths_bits, tree_from_lengths, write_code_lengths, MAX_CODE_LEN};
+use crate::header::{FileInfo, Header};
+use crate::pool;
+use crate::{Node, BLOCK_SIZE, DEFAULT_MAX_CODE_LEN, EOF_SYMBOL, MAX_BUF_SIZE, SYMBOL_COUNT};
 use bitvec::prelude::*;
-use std::collections::HashMap;
-use std::ffi::OsStr;
-use std::io::{self, Read, Write};
-use std::path::PathBuf;
-use std::sync::{
-    atomic::{AtomicU16, Ordering},
-    mpsc::{channel, sync_channel, Receiver},
-    Arc, Mutex,
-};
-use std::thread::{self, JoinHandle};
-
-fn add_to_lookup(lookup: &mut HashMap<u8, BitVec>, parent_path: BitVec, node: &Node) {
-    match node {
-        Node::Branch(_, l1, l2) => {
-            let mut l1_path = parent_path.clone();
-            l1_path.push(false);
-            add_to_lookup(lookup, l1_path, l1);
-            let mut l2_path = parent_path;
-            l2_path.push(true);
-            add_to_lookup(lookup, l2_path, l2);
-        }
-        Node::Leaf(_, byte) => {
-            lookup.insert(*byte, parent_path);
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+/// One block of input, encoded with `table`. If `new_table` is set, the code lengths get written before it.
+struct PreData {
+    id: usize,
+    table: Arc<Encoder>,
+    new_table: bool,
+    content: Box<[u8]>,
+}
+
+/// The encoded block with the EOF_SYMBOL and its checksum, `table` is only set if its code lengths need to be written
+struct PostData {
+    id: usize,
+    table: Option<Arc<Encoder>>,
+    content: BitVec,
+}
+
+/// Builds the huffman tree for the given symbol counts, the index is the symbol.
+/// Symbols that never occur don't get a leaf.
+/// Nodes with the same count are taken in the order they were created (leaves ordered by symbol first),
+/// so the same counts always give the same tree. Fails if no symbol occurs.
+pub fn build_tree(counter: &[usize]) -> Result<Node> {
+    // the heap only holds (count, id), the nodes wait in `nodes` until they get merged
+    let mut nodes: Vec<Option<Box<Node>>> = Vec::with_capacity(counter.len() * 2);
+    let mut heap = BinaryHeap::with_capacity(counter.len());
+
+    for (b, &c) in counter.iter().enumerate() {
+        if c > 0 {
+            // occurs at least once
+            heap.push(Reverse((c, nodes.len())));
+            nodes.push(Some(Box::new(Node::Leaf(c, b as u16))));
         }
     }
+
+    while heap.len() >= 2 {
+        // first: lowest, second: second lowest
+        let Reverse((c1, i1)) = heap.pop().unwrap();
+        let Reverse((c2, i2)) = heap.pop().unwrap();
+
+        // every id is only in the heap once
+        let l1 = nodes[i1].take().unwrap();
+        let l2 = nodes[i2].take().unwrap();
+
+        heap.push(Reverse((c1 + c2, nodes.len())));
+        nodes.push(Some(Box::new(Node::Branch(c1 + c2, l1, l2))));
+    }
+
+    let Reverse((_, root)) = heap.pop().ok_or(HuffmanError::CorruptTree("No symbol has a code"))?;
+    Ok(*nodes[root].take().unwrap())
 }
 
-/// Writes Leaves as 1 bit followed by byte of the value
-/// Branch starts with 0 bit followed by left and right node
-fn write_tree(node: &Node, out: &mut BinaryWriter<std::fs::File>) -> io::Result<()> {
-    match node {
-        Node::Leaf(_, b) => {
-            out.write_bit(true)?;
-            //println!("Written leaf");
-            out.write_byte(*b)?;
+/// Optimal code lengths where no code is longer than `max_len`, calculated with the package-merge algorithm.
+/// Fails if there are more than 2^max_len used symbols.
+pub fn limited_code_lengths(counter: &[usize], max_len: u8) -> Result<Vec<u8>> {
+    enum Item {
+        Leaf(u16),
+        Package(usize, usize),
+    }
+
+    let mut lengths = vec![0; counter.len()];
+
+    // sorted by count, the symbol decides on equal counts so the result is always the same
+    let mut leaves: Vec<(usize, u16)> = counter
+        .iter()
+        .enumerate()
+        .filter(|(_, &c)| c > 0)
+        .map(|(s, &c)| (c, s as u16))
+        .collect();
+    leaves.sort_unstable();
+
+    if leaves.len() <= 1 {
+        // a single symbol still gets a code length, see `code_lengths`
+        for &(_, s) in &leaves {
+            lengths[s as usize] = 1;
         }
-        Node::Branch(_, l, r) => {
-            out.write_bit(false)?;
-            //println!("Written branch");
+        return Ok(lengths);
+    }
+
+    if max_len >= 64 || leaves.len() > 1 << max_len {
+        return Err(HuffmanError::CorruptTree("Too many symbols for the maximum code length"));
+    }
 
-            write_tree(l, out)?;
-            write_tree(r, out)?;
+    // all leaves and packages, the lists only contain (count, index in items)
+    let mut items: Vec<Item> = leaves.iter().map(|&(_, s)| Item::Leaf(s)).collect();
+    let leaf_list: Vec<(usize, usize)> = leaves.iter().enumerate().map(|(i, &(c, _))| (c, i)).collect();
+    let mut list = leaf_list.clone();
+
+    for _ in 1..max_len {
+        // package: combine neighbours to one item, the last one is dropped if it's alone
+        let mut packages = Vec::with_capacity(list.len() / 2);
+        for pair in list.chunks_exact(2) {
+            items.push(Item::Package(pair[0].1, pair[1].1));
+            packages.push((pair[0].0 + pair[1].0, items.len() - 1));
         }
+
+        // merge: the packages and the leaves sorted by count, leaves first on equal counts
+        let mut merged = Vec::with_capacity(leaf_list.len() + packages.len());
+        let (mut l, mut p) = (0, 0);
+        while l < leaf_list.len() || p < packages.len() {
+            if p == packages.len() || (l < leaf_list.len() && leaf_list[l].0 <= packages[p].0) {
+                merged.push(leaf_list[l]);
+                l += 1;
+            } else {
+                merged.push(packages[p]);
+                p += 1;
+            }
+        }
+
+        list = merged;
     }
 
-    Ok(())
+    // the code length of a symbol is how often it is part of the cheapest 2n - 2 items
+    let mut stack: Vec<usize> = list[..2 * leaves.len() - 2].iter().map(|&(_, i)| i).collect();
+    while let Some(i) = stack.pop() {
+        match items[i] {
+            Item::Leaf(s) => lengths[s as usize] += 1,
+            Item::Package(a, b) => {
+                stack.push(a);
+                stack.push(b);
+            }
+        }
+    }
+
+    Ok(lengths)
 }
 
-struct PreData {
-    id: usize,
-    len: usize,
-    content: Box<[u8]>,
+/// Like `build_tree`, but no code gets longer than `max_code_len` bits.
+/// If the huffman tree is too deep, the code lengths are calculated with `limited_code_lengths`
+/// and the tree is the one of the canonical codes, its counts are 0 then.
+pub fn build_limited_tree(counter: &[usize], max_code_len: u8) -> Result<Node> {
+    let root = build_tree(counter)?;
+
+    if code_lengths(&root).iter().all(|&len| len <= max_code_len) {
+        return Ok(root);
+    }
+
+    tree_from_lengths(&limited_code_lengths(counter, max_code_len)?)
 }
 
-struct PostData {
-    id: usize,
-    content: BitVec,
+pub struct Statistics {
+    pub read_bytes: usize,
+    pub written_bytes: usize,
 }
 
-pub fn encode(path: PathBuf) -> io::Result<()> {
-    let mut file = std::fs::File::open(&path)?;
+/// Encodes data with a fixed huffman tree.
+/// The data is split into chunks of MAX_BUF_SIZE, which get encoded by worker threads
+/// and are written in order by a writer thread.
+pub struct Encoder {
+    root: Node,
+    lengths: Vec<u8>,
+    max_code_len: u8,
+    lookup: HashMap<u16, BitVec>,
+    thread_count: usize,
+    original_size: Option<u64>,
+    file_info: Option<FileInfo>,
+}
 
-    struct Statistics {
-        read_bytes: usize,
-        written_bytes: usize,
-    };
+impl Encoder {
+    /// Fails if the tree has codes longer than 64 bits
+    pub fn new(root: Node) -> Result<Self> {
+        // only the code lengths are taken from the tree, the codes are the canonical ones
+        let lengths = code_lengths(&root);
 
-    let mut stats = Statistics {
-        read_bytes: 0,
-        written_bytes: 0,
-    };
+        // now create a lookup table
+        let mut lookup: HashMap<u16, BitVec> = HashMap::new();
 
-    // calculate how many threads are needed
+        for (symbol, code, len) in canonical_codes(&lengths)? {
+            let mut path = BitVec::with_capacity(len as usize);
+            for i in (0..len).rev() {
+                path.push((code >> i) & 1 == 1);
+            }
+            lookup.insert(symbol, path);
+        }
+
+        Ok(Encoder {
+            root,
+            // later blocks may get their own tables, they can use codes up to the default length
+            max_code_len: lengths.iter().copied().max().unwrap_or(1).max(DEFAULT_MAX_CODE_LEN),
+            lengths,
+            lookup,
+            thread_count: crate::MAX_WORKERS,
+            original_size: None,
+            file_info: None,
+        })
+    }
 
-    let thread_count = std::cmp::min(
-        crate::MAX_WORKERS - 1,
-        file.metadata().expect("File metadata error").len() as usize / MAX_BUF_SIZE,
-    ) + 1;
+    /// Creates the Encoder from byte counts, the EOF_SYMBOL gets added.
+    /// `max_code_len` is the longest code that may be used, it's at least 9 so all symbols fit.
+    pub fn from_counts(counts: &FrequencyTable, max_code_len: u8) -> Self {
+        let mut counter = [0; SYMBOL_COUNT];
+        counter[..256].copy_from_slice(counts.counts());
 
-    println!("Worker threads used: {}", thread_count);
+        // the end symbol
+        counter[EOF_SYMBOL as usize] = 1;
 
-    let mut r_buf: Vec<u8> = vec![0; MAX_BUF_SIZE];
-    let mut counter: [usize; 256] = [0; 256];
+        let max_code_len = max_code_len.clamp(MIN_CODE_LEN_LIMIT, MAX_CODE_LEN);
 
-    while let Ok(bytes_read) = file.read(&mut r_buf) {
-        stats.read_bytes += bytes_read;
+        // the EOF_SYMBOL always occurs and 9 bits are enough for all symbols
+        let mut encoder = build_limited_tree(&counter, max_code_len)
+            .and_then(Encoder::new)
+            .expect("Limited trees have short codes");
+        encoder.max_code_len = max_code_len;
+        encoder
+    }
+
+    pub fn tree(&self) -> &Node {
+        &self.root
+    }
+
+    pub fn set_thread_count(&mut self, thread_count: usize) {
+        self.thread_count = thread_count.clamp(1, crate::MAX_WORKERS);
+    }
+
+    /// Size of the input, it's stored in the header so the decoder can check it
+    pub fn set_original_size(&mut self, size: u64) {
+        self.original_size = Some(size);
+    }
 
-        if bytes_read == 0 {
-            break;
+    /// Name, modification time and permissions of the input file, they are stored in the header
+    pub fn set_file_info(&mut self, info: FileInfo) {
+        self.file_info = Some(info);
+    }
+
+    /// Writes all bytes of `data`, the EOF_SYMBOL and the checksum as one block, with the code lengths if `new_table` is set.
+    /// All bytes of `data` need to be in the tree.
+    pub(crate) fn write_block<W: Write>(&self, data: &[u8], new_table: bool, out: &mut BinaryWriter<W>) -> Result<()> {
+        out.write_bit(new_table)?;
+        if new_table {
+            write_code_lengths(&self.lengths, out)?;
+        }
+
+        for byte in data {
+            match self.lookup.get(&(*byte as u16)) {
+                Some(path_vec) => out.write_path(path_vec)?,
+                None => return Err(HuffmanError::MissingSymbol(*byte as u16)),
+            }
         }
 
-        for byte in &r_buf {
-            counter[*byte as usize] += 1;
+        match self.lookup.get(&EOF_SYMBOL) {
+            Some(path_vec) => out.write_path(path_vec)?,
+            None => return Err(HuffmanError::MissingSymbol(EOF_SYMBOL)),
         }
+
+        // checksum of the original data of the block
+        out.write_bits(crc32(data) as usize, 32)?;
+        Ok(())
     }
 
-    // the end byte
-    counter[0x1c] = 1;
+    /// Writes the header and the encoded `input` to `output`.
+    /// The input is split into blocks, the first one starts with the table of this Encoder.
+    /// When the bytes of a block are different enough, it gets its own table.
+    pub fn encode<R: Read, W: Write + Send>(&self, mut input: R, output: W) -> Result<Statistics> {
+        let mut stats = Statistics {
+            read_bytes: 0,
+            written_bytes: 0,
+        };
+
+        let mut writer = BinaryWriter::new(output);
+
+        // write header
+        let mut header = Header::new(self.max_code_len, self.original_size);
+        if let Some(info) = &self.file_info {
+            header = header.with_file_info(info.clone());
+        }
+        header.write(&mut writer.writer)?;
+        let blocks_start = writer.get_bits_written();
+
+        // the same canonical codes as this Encoder, but it can be shared with the workers
+        let mut table = Encoder::new(tree_from_lengths(&self.lengths)?)?;
+        table.max_code_len = self.max_code_len;
+        let mut table = Arc::new(table);
+
+        let mut r_buf: Vec<u8> = vec![0; MAX_BUF_SIZE];
+        let mut stream_crc = Crc32::new();
+        let mut next_id = 0;
+
+        let blocks = std::iter::from_fn(|| {
+            let bytes_read = match fill_buf(&mut input, &mut r_buf) {
+                // an empty input still needs one block with the EOF_SYMBOL
+                Ok(0) if next_id > 0 => return None,
+                Ok(bytes_read) => bytes_read,
+                Err(e) => return Some(Err(e.into())),
+            };
+            stats.read_bytes += bytes_read;
+            stream_crc.update(&r_buf[..bytes_read]);
+
+            // the first block always needs its table, after that only if a fresh one is cheaper
+            let new_table = match fresh_table(&table, &FrequencyTable::from_slice(&r_buf[..bytes_read]), next_id > 0) {
+                Some(fresh) if bytes_read > 0 => {
+                    table = Arc::new(fresh);
+                    true
+                }
+                _ => next_id == 0,
+            };
+
+            next_id += 1;
+            Some(Ok(PreData {
+                id: next_id - 1,
+                table: table.clone(),
+                new_table,
+                content: r_buf[..bytes_read].into(),
+            }))
+        });
+
+        let mut index: Vec<(usize, usize)> = Vec::new();
+        pool::ordered(self.thread_count, blocks, |data| encode_pre_data(&data), |p_dat| {
+            write_post_data(&mut writer, &p_dat, &mut index)?;
+            Ok(())
+        })?;
+
+        // no more blocks, then the checksum of the whole input
+        writer.write_bit(false)?;
+        writer.write_bits(stream_crc.value() as usize, 32)?;
+        write_index(&mut writer, &index, blocks_start)?;
+
+        writer.finish()?;
+        stats.written_bytes = writer.get_bytes_written();
 
-    // create boxed nodes
-    let mut tree: Vec<Box<Node>> = Vec::new();
+        Ok(stats)
+    }
+}
 
-    for b in 0..256 {
-        let c = counter[b];
-        if c > 0 {
-            // occurs at least once
-            tree.push(Box::new(Node::Leaf(c, b as u8)));
+/// Encodes the bytes of one block, its EOF_SYMBOL and its checksum
+fn encode_pre_data(data: &PreData) -> Result<PostData> {
+    let lookup = &data.table.lookup;
+    let mut compressed: BitVec<BigEndian, u8> = BitVec::with_capacity(MAX_BUF_SIZE);
+
+    for &byte in &data.content[..] {
+        // get path to byte
+        match lookup.get(&(byte as u16)) {
+            Some(path_vec) => compressed.extend(path_vec),
+            None => return Err(HuffmanError::MissingSymbol(byte as u16)),
         }
     }
 
-    while tree.len() >= 2 {
-        // first elmt: lowest, second: second lowest
-        let mut lowest_two = if tree[0].count() < tree[1].count() { (0usize, 1usize) } else { (1usize, 0usize) };
+    // add end symbol
+    match lookup.get(&EOF_SYMBOL) {
+        Some(path_vec) => compressed.extend(path_vec),
+        None => return Err(HuffmanError::MissingSymbol(EOF_SYMBOL)),
+    }
+
+    let crc = crc32(&data.content[..]);
+    for i in (0..32).rev() {
+        compressed.push((crc >> i) & 1 == 1);
+    }
 
-        for i in 2..tree.len() {
-            let count = tree[i].count();
-            if count < tree[lowest_two.0].count() {
-                // i gets new lowest, lowest_two.0 gets second lowest
-                lowest_two = (i, lowest_two.0);
-            } else if count < tree[lowest_two.1].count() {
-                // i is new second lowest
-                lowest_two = (lowest_two.0, i);
-            }
+    Ok(PostData {
+        id: data.id,
+        table: if data.new_table { Some(data.table.clone()) } else { None },
+        content: compressed,
+    })
+}
+
+/// Reads until `buf` is full or the input ends, so the blocks don't depend on how much each read returns.
+/// Returns the number of bytes read.
+fn fill_buf<R: Read>(input: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+
+    while len < buf.len() {
+        match input.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(bytes_read) => len += bytes_read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
         }
+    }
+
+    Ok(len)
+}
+
+/// Writes one block from a worker, every block but the first is announced with a 1 bit.
+/// The position and length of the block in bits get added to `index`.
+fn write_post_data<W: Write>(writer: &mut BinaryWriter<W>, p_dat: &PostData, index: &mut Vec<(usize, usize)>) -> io::Result<()> {
+    if p_dat.id > 0 {
+        writer.write_bit(true)?;
+    }
+
+    let start = writer.get_bits_written();
+
+    writer.write_bit(p_dat.table.is_some())?;
+    if let Some(table) = &p_dat.table {
+        write_code_lengths(&table.lengths, writer)?;
+    }
+
+    writer.write_path(&p_dat.content)?;
+
+    index.push((start, writer.get_bits_written() - start));
+    Ok(())
+}
 
-        //println!("len: {} lowest: {:?}", tree.len(), lowest_two);
+/// Writes the block index after the last block, so blocks can be decoded in parallel.
+/// It starts at the next byte, each block has its offset in bits after `blocks_start` and its length in bits (u64 big endian),
+/// the last 8 bytes are the number of blocks.
+fn write_index<W: Write>(writer: &mut BinaryWriter<W>, index: &[(usize, usize)], blocks_start: usize) -> io::Result<()> {
+    // pad to a full byte
+    writer.finish()?;
 
-        if lowest_two.0 < lowest_two.1 {
-            lowest_two = (lowest_two.1, lowest_two.0);
+    for &(start, len) in index {
+        for byte in ((start - blocks_start) as u64).to_be_bytes().iter().chain(&(len as u64).to_be_bytes()) {
+            writer.write_byte(*byte)?;
         }
+    }
 
-        // now we got the lowest two
-        let combined_count = tree[lowest_two.0].count() + tree[lowest_two.1].count();
+    for byte in &(index.len() as u64).to_be_bytes() {
+        writer.write_byte(*byte)?;
+    }
+
+    Ok(())
+}
 
-        // move them out of the vec
-        let l1 = tree.remove(lowest_two.0);
-        let l2 = tree.remove(lowest_two.1);
+/// Number of bits the data of a block with `counts` needs with the code `lengths`,
+/// None if a byte has no code
+fn block_bits(lengths: &[u8], counts: &FrequencyTable) -> Option<usize> {
+    let mut bits = lengths[EOF_SYMBOL as usize] as usize;
 
-        let branch = Node::Branch(combined_count, l1, l2);
-        tree.push(Box::new(branch));
+    for (&count, &len) in counts.counts().iter().zip(lengths) {
+        if count > 0 && len == 0 {
+            return None;
+        }
+        bits += count * len as usize;
     }
 
-    let root = tree.remove(0);
-    std::mem::drop(tree);
+    Some(bits)
+}
+
+/// Returns a new table for a block with `counts` if it's cheaper than `prev`, including its code lengths.
+/// If the decoder doesn't know `prev` yet (`prev_known` not set), its code lengths count as well.
+fn fresh_table(prev: &Encoder, counts: &FrequencyTable, prev_known: bool) -> Option<Encoder> {
+    let fresh = Encoder::from_counts(counts, prev.max_code_len);
+
+    let prev_bits = block_bits(&prev.lengths, counts)
+        .map(|bits| if prev_known { bits } else { bits + code_lengths_bits(&prev.lengths) });
+    let fresh_bits = block_bits(&fresh.lengths, counts)? + code_lengths_bits(&fresh.lengths);
 
-    if cfg!(debug_assertions) {
-        //println!("{:#?}", root);
+    match prev_bits {
+        Some(prev_bits) if prev_bits <= fresh_bits => None,
+        _ => Some(fresh),
     }
+}
 
-    // now create a lookup table
-    let mut lookup: HashMap<u8, BitVec> = HashMap::new();
+/// With 9 bits there are enough codes for all symbols
+const MIN_CODE_LEN_LIMIT: u8 = 9;
 
-    add_to_lookup(&mut lookup, BitVec::new(), &root);
+/// calculate how many threads are needed for `len` bytes
+fn thread_count_for(len: usize) -> usize {
+    std::cmp::min(crate::MAX_WORKERS - 1, len / MAX_BUF_SIZE) + 1
+}
 
+/// Compresses `data` in memory, the result is the same as the content of a `.huff` file.
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut encoder = Encoder::from_counts(&FrequencyTable::from_slice(data), DEFAULT_MAX_CODE_LEN);
+    encoder.set_thread_count(thread_count_for(data.len()));
+    encoder.set_original_size(data.len() as u64);
 
-    println!("Created Lookup table, starting encoding...");
+    let mut out = Vec::new();
+    encoder.encode(data, &mut out).expect("Writing to a Vec can't fail");
 
-    file = std::fs::File::open(&path)?;
-    let mut out_path = path.clone();
+    out
+}
 
-    let extension = match path.extension() {
-        Some(e) => {
-            let mut e = e.to_os_string();
-            e.push(OsStr::new(".huff"));
-            e
-        }
-        None => OsStr::new("huff").to_os_string(),
-    };
-
-    out_path.set_extension(extension);
-
-    println!("File will be saved @ {:?}", &out_path);
-
-    let mut writer = BinaryWriter::new(std::fs::File::create(&out_path)?);
-
-    // write header
-    println!("Writing header");
-    writer
-        .writer
-        .write(HEADER)
-        .map_err(|_e| io::Error::new(io::ErrorKind::Other, "Error while writing header"))?;
-
-    // write tree
-
-    // for each node, if is leaf, write 1 and 8 bit value
-    //                if is branch, write 0 and recursively write node
-    println!("Writing tree");
-    write_tree(&root, &mut writer)?;
-
-    let mut workers: Vec<JoinHandle<()>> = Vec::with_capacity(thread_count);
-    let lookup = Arc::new(lookup);
-    let (pre_sender, pre_receiver) = sync_channel::<Option<PreData>>(10);
-    let (post_sender, post_receiver) = channel::<PostData>();
-    let workers_active = Arc::new(AtomicU16::new(0));
-    let postdata_waiting = Arc::new(AtomicU16::new(0));
-
-    let feed: Arc<Mutex<Receiver<Option<PreData>>>> = Arc::new(Mutex::new(pre_receiver));
-
-    for t_id in 0..thread_count {
-        let feed = feed.clone();
-        let lookup = lookup.clone();
-        let post_sender = post_sender.clone();
-        let workers_active = workers_active.clone();
-        let postdata_waiting = postdata_waiting.clone();
-
-        // tell worker "will" be active
-        workers_active.fetch_add(1, Ordering::Relaxed);
-
-        workers.push(thread::Builder::new().name(format!("worker_{}", t_id)).spawn(move || {
-            println!("thread {} waiting", t_id);
-            let mut total_time_working = 0u64;
-            let mut bytes_processed = 0u64;
-
-            loop {
-
-                let data = {
-                    match feed.lock().expect("Feed Mutex poisoned").recv().unwrap() {
-                        Some(data) => data,
-                        None => break
-                    }
-                };
-                bytes_processed += data.len as u64;
-                let start_time = std::time::Instant::now();
-                //println!("[w{}] received {} bytes", t_id, data.len);
-                let mut compressed: BitVec<BigEndian, u8> = BitVec::with_capacity(MAX_BUF_SIZE);
-
-                for i in 0..data.len {
-                    // get path to byte
-                    match lookup.get(&data.content[i]) {
-                        Some(path_vec) => compressed.extend(path_vec),
-                        None => {
-                            panic!("Byte not in lookup-table");
-                        }
-                    }
-                }
+/// Compresses everything written to it without knowing the whole input.
+/// The data is buffered and every `block_size` bytes a block gets written,
+/// with its own tree if that's cheaper than the one of the previous block.
+/// Call `finish` to write the last block, otherwise it's done on drop and errors are ignored.
+pub struct HuffmanWriter<W: Write> {
+    writer: BinaryWriter<W>,
+    buf: Vec<u8>,
+    block_size: usize,
+    max_code_len: u8,
+    table: Option<Encoder>,
+    blocks_start: usize,
+    index: Vec<(usize, usize)>,
+    crc: Crc32,
+    finished: bool,
+}
 
-                //println!("[w{}] sends {} bits", t_id, compressed.len());
-                // send data to writer thread
-                postdata_waiting.fetch_add(1, Ordering::Relaxed);
-                post_sender.send(PostData {
-                    id: data.id,
-                    content: compressed
-                }).expect("Could not send PostData");
+impl<W: Write> HuffmanWriter<W> {
+    pub fn new(inner: W) -> Result<Self> {
+        HuffmanWriter::with_block_size(inner, BLOCK_SIZE)
+    }
 
-                
-                total_time_working += start_time.elapsed().as_nanos() as u64;
-            }
-            println!("[w{}] finished | avg time per byte: {}ns", t_id, total_time_working / bytes_processed );
-            workers_active.fetch_sub(1, Ordering::Relaxed);
-        }).unwrap());
+    pub fn with_block_size(inner: W, block_size: usize) -> Result<Self> {
+        HuffmanWriter::with_options(inner, block_size, DEFAULT_MAX_CODE_LEN)
     }
 
-    println!("Workers active: {}", workers_active.load(Ordering::Relaxed));
+    /// `max_code_len` is the longest code that may be used, it's at least 9 so all symbols fit.
+    pub fn with_options(inner: W, block_size: usize, max_code_len: u8) -> Result<Self> {
+        let max_code_len = max_code_len.clamp(MIN_CODE_LEN_LIMIT, MAX_CODE_LEN);
+
+        let mut writer = BinaryWriter::new(inner);
+        // the size isn't known before everything is written
+        Header::new(max_code_len, None).write(&mut writer.writer)?;
+        let blocks_start = writer.get_bits_written();
+
+        Ok(HuffmanWriter {
+            writer,
+            buf: Vec::with_capacity(block_size),
+            block_size: block_size.max(1),
+            max_code_len,
+            table: None,
+            blocks_start,
+            index: Vec::new(),
+            crc: Crc32::new(),
+            finished: false,
+        })
+    }
 
-    // writer thread
-    let writer_thread = thread::Builder::new().name("writer".to_owned()).spawn(move || {
-        println!("Writer-thread running");
+    pub fn get_ref(&self) -> &W {
+        &self.writer.writer
+    }
 
-        let mut buf: Vec<PostData> = Vec::new();
-        let mut next_expected: usize = 0;
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer.writer
+    }
 
-        while workers_active.load(Ordering::Relaxed) > 0 || postdata_waiting.load(Ordering::Relaxed) > 0  {
-            let p_dat = post_receiver.recv().unwrap();
-            postdata_waiting.fetch_sub(1, Ordering::Relaxed);
-            //println!("writer received {} bits", p_dat.content.len());
-            // case 1: p_dat ist next expected package
-            if p_dat.id == next_expected {
-                writer.write_path(&p_dat.content).unwrap();
-                next_expected += 1;
+    fn write_block(&mut self) -> Result<()> {
+        if !self.index.is_empty() {
+            // tell the decoder another block follows
+            self.writer.write_bit(true)?;
+        }
 
-                let mut n_idx = 0;
+        let start = self.writer.get_bits_written();
 
-                while n_idx < buf.len() && buf[n_idx].id == next_expected {
-                    writer.write_path(&p_dat.content).unwrap();
-                    next_expected += 1;
-                    n_idx += 1;
-                }
+        let counts = FrequencyTable::from_slice(&self.buf);
+        let fresh = match &self.table {
+            Some(prev) => fresh_table(prev, &counts, true),
+            None => Some(Encoder::from_counts(&counts, self.max_code_len)),
+        };
 
-                if n_idx > 0 {
-                    buf.drain(0..n_idx);
-                }
-            }
+        let new_table = fresh.is_some();
+        if fresh.is_some() {
+            self.table = fresh;
+        }
 
-            // case 2: p_dat is somewhere in buf
-            else if !buf.is_empty() && p_dat.id < buf.last().unwrap().id {
-                // find where to insert
-                let mut pos = 0;
-                while pos < buf.len() {
-                    if buf[pos].id > p_dat.id {
-                        break;
-                    }
-                    pos += 1;
-                }
+        if let Some(table) = &self.table {
+            table.write_block(&self.buf, new_table, &mut self.writer)?;
+        }
 
-                buf.insert(pos, p_dat);
-            }
-            // case 3: p_dat is at the end
-            else {
-                buf.push(p_dat);
-            }
+        self.index.push((start, self.writer.get_bits_written() - start));
+        self.crc.update(&self.buf);
+        self.buf.clear();
+
+        Ok(())
+    }
+
+    /// Writes the buffered data as the last block and flushes everything.
+    pub fn finish(&mut self) -> Result<()> {
+        if self.finished {
+            return Ok(());
         }
 
-        if !buf.is_empty() {
-            eprintln!("Still got {} unproccessed packages", buf.len());
-            eprintln!("First unprocessed id: {}", buf[0].id);
-            panic!("Not all packets processed");
+        // an empty input still needs one block with the EOF_SYMBOL
+        if !self.buf.is_empty() || self.index.is_empty() {
+            self.write_block()?;
         }
 
-        // add finish byte
-        match lookup.get(&0x1c) {
-            Some(path_vec) => writer.write_path(path_vec).unwrap(),
-            None => {
-                panic!("FileSeperator Byte not in lookup-table");
-            }
+        self.writer.write_bit(false)?;
+        self.writer.write_bits(self.crc.value() as usize, 32)?;
+        write_index(&mut self.writer, &self.index, self.blocks_start)?;
+        self.writer.finish()?;
+        self.finished = true;
+
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for HuffmanWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.finished {
+            return Err(io::Error::other("HuffmanWriter is already finished"));
         }
 
-        stats.written_bytes = writer.get_bytes_written();
+        let len = std::cmp::min(data.len(), self.block_size - self.buf.len());
+        self.buf.extend_from_slice(&data[..len]);
+
+        if self.buf.len() >= self.block_size {
+            self.write_block()?;
+        }
+
+        Ok(len)
+    }
+
+    /// Only writes the complete bytes, the current block is continued afterwards
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.write_buf()?;
+        self.writer.writer.flush()
+    }
+}
+
+impl<W: Write> Drop for HuffmanWriter<W> {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+/// Compresses the file at `path` to `output`. The file is read twice, once to count the bytes and once to encode them.
+/// If `file_info` is given, it's stored in the header.
+pub fn encode_file<W: Write + Send>(path: &Path, output: W, file_info: Option<FileInfo>) -> Result<Statistics> {
+    let mut file = std::fs::File::open(path)?;
+
+    // calculate how many threads are needed
+    let thread_count = thread_count_for(file.metadata()?.len() as usize);
+
+    let counter = FrequencyTable::from_reader(&mut file)?;
+
+    let mut encoder = Encoder::from_counts(&counter, DEFAULT_MAX_CODE_LEN);
+    encoder.set_thread_count(thread_count);
+    encoder.set_original_size(counter.total() as u64);
+    if let Some(info) = file_info {
+        encoder.set_file_info(info);
+    }
+
+    file.seek(SeekFrom::Start(0))?;
+    encoder.encode(file, output)
+}
+
+#[test]
+fn huffman_writer_blocks() -> io::Result<()> {
+    let data = std::fs::read("./test.txt")?;
+
+    let mut out = Vec::new();
+    {
+        let mut writer = HuffmanWriter::with_block_size(&mut out, 300)?;
+        io::copy(&mut &data[..], &mut writer)?;
+        writer.finish()?;
+    }
 
-        println!(
-            " --- Stats ---\nBytes read: {}\nBytes written: {}\nCompression rate: {}%\n",
-            stats.read_bytes,
-            stats.written_bytes,
-            (stats.read_bytes as f64 / stats.written_bytes as f64) * 100.0
-        );
-    }).unwrap();
+    assert_eq!(crate::decompress(&out)?, data);
 
+    let mut empty = Vec::new();
+    HuffmanWriter::new(&mut empty)?.finish()?;
+    assert_eq!(crate::decompress(&empty)?, b"");
 
-    let mut pre_id = 0;
-    while let Ok(bytes_read) = file.read(&mut r_buf) {
-        if bytes_read == 0 {
-            break;
+    Ok(())
+}
+
+#[test]
+fn limited_tree() -> Result<()> {
+    // doubling counts would give codes of up to 19 bits
+    let counter: Vec<usize> = (0..20).map(|i| 1 << i).collect();
+
+    let lengths = code_lengths(&build_limited_tree(&counter, 12)?);
+    assert_eq!(lengths.iter().max(), Some(&12));
+
+    // the limited codes still need to form a complete tree
+    canonical_codes(&lengths)?;
+
+    // if the limit isn't reached, it's the normal huffman tree
+    assert_eq!(code_lengths(&build_limited_tree(&counter, 19)?), code_lengths(&build_tree(&counter)?));
+
+    // no lengths of at most 3 bits that fit in a tree are cheaper, found by trying all of them
+    let small = [1, 1, 2, 4, 8, 16, 3];
+    let cost = |lengths: &[u8]| -> usize { small.iter().zip(lengths).map(|(&c, &l)| c * l as usize).sum() };
+    let best = (0..3usize.pow(small.len() as u32))
+        .map(|n| (0..small.len()).map(|i| (n / 3usize.pow(i as u32) % 3 + 1) as u8).collect::<Vec<u8>>())
+        .filter(|lengths| lengths.iter().map(|&l| 1 << (3 - l)).sum::<usize>() <= 8)
+        .map(|lengths| cost(&lengths))
+        .min();
+    assert_eq!(Some(cost(&limited_code_lengths(&small, 3)?)), best);
+
+    // 20 symbols don't fit in codes of 4 bits, and nothing to build a tree from
+    assert!(matches!(build_limited_tree(&counter, 4), Err(HuffmanError::CorruptTree(_))));
+    assert!(matches!(build_tree(&[0; SYMBOL_COUNT]), Err(HuffmanError::CorruptTree(_))));
+
+    Ok(())
+}
+
+#[test]
+fn build_tree_ties() -> Result<()> {
+    // all counts equal: every order of merging is valid, but the result has to be the same each time
+    let counter = [7; 256];
+
+    let lengths = code_lengths(&build_tree(&counter)?);
+    assert!(lengths[..256].iter().all(|&len| len == 8));
+    assert_eq!(build_tree(&counter)?.count(), 7 * 256);
+
+    let counter: Vec<usize> = (0..300).map(|i| i % 5 + 1).collect();
+    assert_eq!(format!("{:?}", build_tree(&counter)?), format!("{:?}", build_tree(&counter)?));
+
+    Ok(())
+}
+
+#[test]
+fn block_tables() -> io::Result<()> {
+    let text = std::fs::read("./test.txt")?;
+    let table = Encoder::from_counts(&FrequencyTable::from_slice(&text), DEFAULT_MAX_CODE_LEN);
+
+    // the same bytes again: the table can be reused
+    assert!(fresh_table(&table, &FrequencyTable::from_slice(&text), true).is_none());
+
+    // bytes the table doesn't know need a new one
+    let other: Vec<u8> = (128..=255u8).cycle().take(2000).collect();
+    assert!(fresh_table(&table, &FrequencyTable::from_slice(&other), true).is_some());
+
+    // text, other bytes, text again
+    let data: Vec<u8> = text.iter().chain(&other).chain(&text).copied().collect();
+
+    let mut out = Vec::new();
+    {
+        let mut writer = HuffmanWriter::with_block_size(&mut out, 1000)?;
+        writer.write_all(&data)?;
+        writer.finish()?;
+    }
+
+    assert_eq!(crate::decompress(&out)?, data);
+
+    Ok(())
+}
+
+#[test]
+fn encode_errors() {
+    /// Fails like a full disk after some bytes
+    struct FullDisk(usize);
+
+    impl Write for FullDisk {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            if data.len() > self.0 {
+                return Err(io::Error::other("No space left on device"));
+            }
+            self.0 -= data.len();
+            Ok(data.len())
         }
-        //println!("Sending {} bytes for proceccing", bytes_read);
-        // fill queue
-        pre_sender.send(Some(PreData {
-            id: pre_id,
-            len: bytes_read,
-            content: r_buf.clone().into_boxed_slice()
-        })).expect("Sending PreData failed");
 
-        pre_id += 1;
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
     }
 
-    // terminate workers
-    for _ in &workers {
-        pre_sender.send(None).unwrap();
+    /// Returns some data, then fails
+    struct BrokenReader(usize);
+
+    impl Read for BrokenReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0 == 0 {
+                return Err(io::Error::other("Connection reset"));
+            }
+            let len = std::cmp::min(self.0, buf.len());
+            buf[..len].iter_mut().for_each(|b| *b = b'a');
+            self.0 -= len;
+            Ok(len)
+        }
     }
 
-    for t in workers {
-        t.join().unwrap();
+    let data: Vec<u8> = (0..MAX_BUF_SIZE * 4).map(|i| (i % 7) as u8).collect();
+    let mut encoder = Encoder::from_counts(&FrequencyTable::from_slice(&data), DEFAULT_MAX_CODE_LEN);
+    encoder.set_thread_count(4);
+
+    assert!(matches!(encoder.encode(&data[..], FullDisk(1000)), Err(HuffmanError::Io(_))));
+    assert!(matches!(encoder.encode(BrokenReader(MAX_BUF_SIZE * 3), Vec::new()), Err(HuffmanError::Io(_))));
+}
+
+#[test]
+fn short_reads() -> Result<()> {
+    /// Returns at most one byte per read, like a slow socket would
+    struct OneByteReader<'a>(&'a [u8]);
+
+    impl<'a> Read for OneByteReader<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match (self.0.split_first(), buf.first_mut()) {
+                (Some((b, rest)), Some(out)) => {
+                    *out = *b;
+                    self.0 = rest;
+                    Ok(1)
+                }
+                _ => Ok(0),
+            }
+        }
     }
-    let workers_finished = std::time::Instant::now();
 
-    writer_thread.join().unwrap();    
+    let data: Vec<u8> = std::fs::read("./test.txt")?.into_iter().cycle().take(MAX_BUF_SIZE * 2 + 100).collect();
+
+    let mut encoder = Encoder::from_counts(&FrequencyTable::from_slice(&data), DEFAULT_MAX_CODE_LEN);
+    encoder.set_original_size(data.len() as u64);
+
+    let mut compressed = Vec::new();
+    encoder.encode(OneByteReader(&data), &mut compressed)?;
+    assert!(compressed == compress(&data));
+
+    Ok(())
+}
+
+#[test]
+fn multi_thread_roundtrip() -> Result<()> {
+    // many more chunks than workers, so they finish out of order
+    let data: Vec<u8> = (0..MAX_BUF_SIZE * 8 + 123)
+        .map(|i| ((i / 1000) as u8).wrapping_mul(31) ^ (i % 13) as u8)
+        .collect();
+
+    let mut encoder = Encoder::from_counts(&FrequencyTable::from_slice(&data), DEFAULT_MAX_CODE_LEN);
+    encoder.set_thread_count(crate::MAX_WORKERS);
+
+    let mut compressed = Vec::new();
+    encoder.encode(&data[..], &mut compressed)?;
 
-    println!("Writer thread continued for {}s", workers_finished.elapsed().as_secs_f64());
+    assert!(crate::decompress(&compressed)? == data);
 
     Ok(())
 }
@@ -0,0 +1,85 @@
+use std::fmt;
+use std::io;
+
+/// Everything that can go wrong while encoding or decoding
+#[derive(Debug)]
+pub enum HuffmanError {
+    /// The input doesn't start with the magic bytes (or the legacy header)
+    BadMagic,
+    /// The file was written by a newer version of the format
+    UnsupportedVersion(u8),
+    /// The header has flags for features that aren't supported
+    UnsupportedFlags(u8),
+    /// A field of the header has an invalid value
+    CorruptHeader(&'static str),
+    /// The tree or the code lengths of a block are invalid
+    CorruptTree(&'static str),
+    /// The blocks or the block index don't fit together
+    CorruptBlock(&'static str),
+    /// The decoded data doesn't match the checksum stored in the file
+    ChecksumMismatch { expected: u32, actual: u32 },
+    /// The input ended in the middle of the stream
+    UnexpectedEof,
+    /// The data has a symbol the tree of the Encoder doesn't have
+    MissingSymbol(u16),
+    /// A thread of the encoder stopped unexpectedly
+    Internal(&'static str),
+    Io(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, HuffmanError>;
+
+impl fmt::Display for HuffmanError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HuffmanError::BadMagic => write!(f, "Not a huffman file"),
+            HuffmanError::UnsupportedVersion(version) => write!(
+                f,
+                "File has format version {}, only versions up to {} are supported",
+                version,
+                crate::header::VERSION
+            ),
+            HuffmanError::UnsupportedFlags(flags) => write!(f, "Flags {:#010b} are not supported", flags),
+            HuffmanError::CorruptHeader(msg) => write!(f, "Corrupt header: {}", msg),
+            HuffmanError::CorruptTree(msg) => write!(f, "Corrupt tree: {}", msg),
+            HuffmanError::CorruptBlock(msg) => write!(f, "Corrupt block: {}", msg),
+            HuffmanError::ChecksumMismatch { expected, actual } => {
+                write!(f, "Checksum mismatch: expected {:08x}, got {:08x}", expected, actual)
+            }
+            HuffmanError::UnexpectedEof => write!(f, "Unexpected end of the input"),
+            HuffmanError::MissingSymbol(symbol) => write!(f, "Symbol {} is not in the tree", symbol),
+            HuffmanError::Internal(msg) => write!(f, "Internal error: {}", msg),
+            HuffmanError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for HuffmanError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            HuffmanError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for HuffmanError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof => HuffmanError::UnexpectedEof,
+            _ => HuffmanError::Io(e),
+        }
+    }
+}
+
+/// For `Read` and `Write` implementations, the HuffmanError is kept as the inner error
+impl From<HuffmanError> for io::Error {
+    fn from(e: HuffmanError) -> Self {
+        match e {
+            HuffmanError::Io(e) => e,
+            HuffmanError::UnexpectedEof => io::Error::new(io::ErrorKind::UnexpectedEof, e),
+            HuffmanError::MissingSymbol(_) => io::Error::new(io::ErrorKind::InvalidInput, e),
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
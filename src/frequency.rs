@@ -0,0 +1,110 @@
+use crate::MAX_BUF_SIZE;
+use std::io::{self, Read};
+
+/// How often each byte occurs, it can be built up from several parts of the input
+#[derive(Clone, Debug, PartialEq)]
+pub struct FrequencyTable {
+    counts: [usize; 256],
+}
+
+impl FrequencyTable {
+    pub fn new() -> Self {
+        FrequencyTable { counts: [0; 256] }
+    }
+
+    pub fn from_slice(data: &[u8]) -> Self {
+        let mut table = FrequencyTable::new();
+        table.add(data);
+        table
+    }
+
+    /// Counts everything until the end of `input`
+    pub fn from_reader<R: Read>(mut input: R) -> io::Result<Self> {
+        let mut table = FrequencyTable::new();
+        let mut r_buf: Vec<u8> = vec![0; MAX_BUF_SIZE];
+
+        loop {
+            match input.read(&mut r_buf) {
+                Ok(0) => break,
+                Ok(bytes_read) => table.add(&r_buf[..bytes_read]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(table)
+    }
+
+    /// Counts the bytes of `data` as well
+    pub fn add(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.counts[byte as usize] += 1;
+        }
+    }
+
+    /// Adds the counts of `other`, like both inputs were counted together
+    pub fn merge(&mut self, other: &FrequencyTable) {
+        for (count, other) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *count += other;
+        }
+    }
+
+    /// Scales all counts down so the largest is at most `max`, bytes that occur keep a count of at least 1
+    pub fn scale(&self, max: usize) -> FrequencyTable {
+        let largest = self.counts.iter().copied().max().unwrap_or(0);
+
+        if largest <= max {
+            return self.clone();
+        }
+
+        let mut scaled = FrequencyTable::new();
+        for (scaled, &count) in scaled.counts.iter_mut().zip(self.counts.iter()) {
+            if count > 0 {
+                *scaled = std::cmp::max(1, (count as u128 * max as u128 / largest as u128) as usize);
+            }
+        }
+
+        scaled
+    }
+
+    pub fn get(&self, byte: u8) -> usize {
+        self.counts[byte as usize]
+    }
+
+    pub fn counts(&self) -> &[usize; 256] {
+        &self.counts
+    }
+
+    /// Number of bytes counted
+    pub fn total(&self) -> usize {
+        self.counts.iter().sum()
+    }
+}
+
+impl Default for FrequencyTable {
+    fn default() -> Self {
+        FrequencyTable::new()
+    }
+}
+
+#[test]
+fn frequency_table() -> io::Result<()> {
+    let data = std::fs::read("./test.txt")?;
+    let (first, second) = data.split_at(700);
+
+    let table = FrequencyTable::from_slice(&data);
+    assert_eq!(table.total(), data.len());
+    assert_eq!(FrequencyTable::from_reader(&data[..])?, table);
+
+    let mut merged = FrequencyTable::from_slice(first);
+    merged.merge(&FrequencyTable::from_slice(second));
+    assert_eq!(merged, table);
+
+    let scaled = table.scale(10);
+    assert!(scaled.counts().iter().all(|&c| c <= 10));
+    for byte in 0..=255u8 {
+        assert_eq!(scaled.get(byte) > 0, table.get(byte) > 0);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,311 @@
+use crate::error::{HuffmanError, Result};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+/// First bytes of every file, the first one isn't ASCII so it can't be mistaken for the legacy header
+pub const MAGIC: [u8; 4] = [0x8e, b'H', b'U', b'F'];
+/// Format version written by this encoder
+pub const VERSION: u8 = 1;
+/// Version reported for files with the legacy header
+pub const LEGACY_VERSION: u8 = 0;
+/// Header of the 0.1 format: a tree of 8-bit leaves followed by the codes, ended by the code of 0x1c
+pub const LEGACY_HEADER: &[u8] = b"HUFFMAN 0.1 Matthias Kind";
+/// magic, version, flags, max code length, original size
+pub const HEADER_LEN: usize = 4 + 1 + 1 + 1 + 8;
+
+/// Each block and the whole stream end with a CRC32
+pub const FLAG_CHECKSUMS: u8 = 1;
+/// The block index is written after the last block
+pub const FLAG_BLOCK_INDEX: u8 = 1 << 1;
+/// Blocks store code lengths of canonical codes
+pub const FLAG_CANONICAL: u8 = 1 << 2;
+/// Symbols are 16 bit wide instead of bytes (not supported yet)
+pub const FLAG_WIDE_SYMBOLS: u8 = 1 << 3;
+/// The original size field is set
+pub const FLAG_ORIGINAL_SIZE: u8 = 1 << 4;
+/// The name, modification time and permissions of the original file follow the fixed fields
+pub const FLAG_FILE_INFO: u8 = 1 << 5;
+
+const SUPPORTED_FLAGS: u8 = FLAG_CHECKSUMS | FLAG_BLOCK_INDEX | FLAG_CANONICAL | FLAG_ORIGINAL_SIZE | FLAG_FILE_INFO;
+
+/// About the file that was compressed, like the FNAME and MTIME fields of gzip.
+/// It's written as modification time (u64 seconds since 1970), Unix permissions (u32)
+/// and the name (u16 length + UTF-8), 0 means unknown for all of them.
+/// Only the read, write and execute bits are kept, never setuid, setgid or sticky.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FileInfo {
+    /// Only the file name, without the directories
+    pub name: Option<String>,
+    /// Seconds since 1970
+    pub mtime: Option<u64>,
+    /// Unix permission bits
+    pub mode: Option<u32>,
+}
+
+/// The permission bits that are stored and restored
+const PERMISSION_BITS: u32 = 0o777;
+
+impl FileInfo {
+    /// Takes the name, modification time and permissions of the file at `path`
+    pub fn from_path(path: &Path) -> io::Result<Self> {
+        let metadata = fs::metadata(path)?;
+
+        #[cfg(unix)]
+        let mode = {
+            use std::os::unix::fs::PermissionsExt;
+            Some(metadata.permissions().mode() & PERMISSION_BITS)
+        };
+        #[cfg(not(unix))]
+        let mode = None;
+
+        Ok(FileInfo {
+            // names that aren't UTF-8 or too long aren't stored
+            name: path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .filter(|name| name.len() <= u16::MAX as usize)
+                .map(|name| name.to_owned()),
+            mtime: metadata
+                .modified()
+                .ok()
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map(|time| time.as_secs())
+                .filter(|&secs| secs > 0),
+            mode: mode.filter(|&mode| mode > 0),
+        })
+    }
+
+    /// Sets the modification time and permissions of the file at `path`, the name is not used
+    pub fn restore(&self, path: &Path) -> io::Result<()> {
+        if let Some(mtime) = self.mtime {
+            fs::OpenOptions::new().write(true).open(path)?.set_modified(UNIX_EPOCH + Duration::from_secs(mtime))?;
+        }
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            if let Some(mode) = self.mode {
+                fs::set_permissions(path, fs::Permissions::from_mode(mode & PERMISSION_BITS))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        // like in from_path, a name that doesn't fit the u16 length isn't stored
+        let name = self.name.as_deref().filter(|name| name.len() <= u16::MAX as usize).unwrap_or("");
+
+        out.write_all(&self.mtime.unwrap_or(0).to_be_bytes())?;
+        out.write_all(&self.mode.unwrap_or(0).to_be_bytes())?;
+        out.write_all(&(name.len() as u16).to_be_bytes())?;
+        out.write_all(name.as_bytes())
+    }
+
+    fn read<R: Read>(input: &mut R) -> Result<Self> {
+        let mut fields = [0; 8 + 4 + 2];
+        input.read_exact(&mut fields)?;
+
+        let mut mtime = [0; 8];
+        mtime.copy_from_slice(&fields[..8]);
+        let mut mode = [0; 4];
+        mode.copy_from_slice(&fields[8..12]);
+
+        let mut name = vec![0; u16::from_be_bytes([fields[12], fields[13]]) as usize];
+        input.read_exact(&mut name)?;
+
+        let name = String::from_utf8(name).map_err(|_| HuffmanError::CorruptHeader("File name is not UTF-8"))?;
+        // the name gets used as a path, so it can't lead into other directories
+        if name == "." || name == ".." || name.contains(['/', '\\', '\0']) {
+            return Err(HuffmanError::CorruptHeader("File name is not valid"));
+        }
+
+        Ok(FileInfo {
+            name: Some(name).filter(|name| !name.is_empty()),
+            mtime: Some(u64::from_be_bytes(mtime)).filter(|&mtime| mtime > 0),
+            // the header can come from anyone, so it can't make a file setuid
+            mode: Some(u32::from_be_bytes(mode) & PERMISSION_BITS).filter(|&mode| mode > 0),
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Header {
+    pub version: u8,
+    pub flags: u8,
+    /// Longest code the encoder was allowed to use, 0 for legacy files
+    pub max_code_len: u8,
+    /// Number of bytes before compression, if it was known when the header was written
+    pub original_size: Option<u64>,
+    pub file_info: Option<FileInfo>,
+}
+
+impl Header {
+    /// Header of the current version with all features this encoder writes
+    pub fn new(max_code_len: u8, original_size: Option<u64>) -> Self {
+        let mut flags = FLAG_CHECKSUMS | FLAG_BLOCK_INDEX | FLAG_CANONICAL;
+        if original_size.is_some() {
+            flags |= FLAG_ORIGINAL_SIZE;
+        }
+
+        Header {
+            version: VERSION,
+            flags,
+            max_code_len,
+            original_size,
+            file_info: None,
+        }
+    }
+
+    /// Stores `info` about the original file as well
+    pub fn with_file_info(mut self, info: FileInfo) -> Self {
+        self.flags |= FLAG_FILE_INFO;
+        self.file_info = Some(info);
+        self
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        self.version == LEGACY_VERSION
+    }
+
+    pub fn has_flag(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+
+    pub fn write<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        out.write_all(&MAGIC)?;
+        out.write_all(&[self.version, self.flags, self.max_code_len])?;
+        out.write_all(&self.original_size.unwrap_or(0).to_be_bytes())?;
+
+        match &self.file_info {
+            Some(info) => info.write(out),
+            None => Ok(()),
+        }
+    }
+
+    /// Reads and checks the header of the current format or the legacy one
+    pub fn read<R: Read>(input: &mut R) -> Result<Self> {
+        let mut magic = [0; 4];
+        input.read_exact(&mut magic)?;
+
+        if magic[..] == LEGACY_HEADER[..4] {
+            let mut rest = vec![0; LEGACY_HEADER.len() - 4];
+            input.read_exact(&mut rest)?;
+
+            if rest[..] != LEGACY_HEADER[4..] {
+                return Err(HuffmanError::BadMagic);
+            }
+
+            return Ok(Header {
+                version: LEGACY_VERSION,
+                flags: 0,
+                max_code_len: 0,
+                original_size: None,
+                file_info: None,
+            });
+        }
+
+        if magic != MAGIC {
+            return Err(HuffmanError::BadMagic);
+        }
+
+        let mut fields = [0; HEADER_LEN - 4];
+        input.read_exact(&mut fields)?;
+
+        let mut size = [0; 8];
+        size.copy_from_slice(&fields[3..]);
+
+        let header = Header {
+            version: fields[0],
+            flags: fields[1],
+            max_code_len: fields[2],
+            original_size: Some(u64::from_be_bytes(size)),
+            file_info: None,
+        };
+
+        if header.version == LEGACY_VERSION || header.version > VERSION {
+            return Err(HuffmanError::UnsupportedVersion(header.version));
+        }
+
+        if header.flags & !SUPPORTED_FLAGS != 0 || !header.has_flag(FLAG_CANONICAL) {
+            return Err(HuffmanError::UnsupportedFlags(header.flags));
+        }
+
+        if header.max_code_len == 0 || header.max_code_len > crate::canonical::MAX_CODE_LEN {
+            return Err(HuffmanError::CorruptHeader("Maximum code length is not valid"));
+        }
+
+        let file_info = if header.has_flag(FLAG_FILE_INFO) {
+            Some(FileInfo::read(input)?)
+        } else {
+            None
+        };
+
+        Ok(Header {
+            original_size: header.original_size.filter(|_| header.has_flag(FLAG_ORIGINAL_SIZE)),
+            file_info,
+            ..header
+        })
+    }
+}
+
+#[test]
+fn header_roundtrip() -> Result<()> {
+    let header = Header::new(15, Some(1234));
+
+    let mut buf = Vec::new();
+    header.write(&mut buf)?;
+    assert_eq!(buf.len(), HEADER_LEN);
+    assert_eq!(Header::read(&mut &buf[..])?, header);
+
+    assert!(Header::read(&mut &LEGACY_HEADER[..])?.is_legacy());
+
+    // a newer version gets rejected with a message
+    buf[4] = VERSION + 1;
+    assert!(matches!(Header::read(&mut &buf[..]), Err(HuffmanError::UnsupportedVersion(v)) if v == VERSION + 1));
+
+    assert!(matches!(Header::read(&mut &b"PK\x03\x04"[..]), Err(HuffmanError::BadMagic)));
+
+    let info = FileInfo {
+        name: Some("test.txt".to_owned()),
+        mtime: Some(1_600_000_000),
+        mode: Some(0o644),
+    };
+    let header = Header::new(15, None).with_file_info(info.clone());
+    buf.clear();
+    header.write(&mut buf)?;
+    assert_eq!(Header::read(&mut &buf[..])?.file_info, Some(info.clone()));
+
+    // setuid, setgid, sticky and higher bits are dropped
+    let header = Header::new(15, None).with_file_info(FileInfo {
+        mode: Some(0xffff_f000 | 0o7755),
+        ..info
+    });
+    buf.clear();
+    header.write(&mut buf)?;
+    assert_eq!(Header::read(&mut &buf[..])?.file_info.and_then(|info| info.mode), Some(0o755));
+
+    // a name that is too long gets dropped instead of cut off
+    let header = Header::new(15, None).with_file_info(FileInfo {
+        name: Some("a".repeat(u16::MAX as usize + 1)),
+        mtime: Some(1_600_000_000),
+        ..FileInfo::default()
+    });
+    buf.clear();
+    header.write(&mut buf)?;
+    let read = Header::read(&mut &buf[..])?.file_info.expect("File info is missing");
+    assert_eq!((read.name, read.mtime), (None, Some(1_600_000_000)));
+
+    // a stored name can't point to another directory
+    let header = Header::new(15, None).with_file_info(FileInfo {
+        name: Some("../test.txt".to_owned()),
+        ..FileInfo::default()
+    });
+    buf.clear();
+    header.write(&mut buf)?;
+    assert!(matches!(Header::read(&mut &buf[..]), Err(HuffmanError::CorruptHeader(_))));
+
+    Ok(())
+}
@@ -0,0 +1,276 @@
+use crate::decode::{read_index_at, Decoder, LEGACY_EOF};
+use crate::error::Result;
+use crate::header::{Header, FLAG_BLOCK_INDEX, FLAG_CANONICAL, FLAG_CHECKSUMS, FLAG_FILE_INFO, FLAG_ORIGINAL_SIZE, FLAG_WIDE_SYMBOLS};
+use crate::{Node, EOF_SYMBOL};
+use std::fmt::Write;
+use std::io::{Read, Seek, SeekFrom};
+
+const FLAG_NAMES: [(u8, &str); 6] = [
+    (FLAG_CHECKSUMS, "checksums"),
+    (FLAG_BLOCK_INDEX, "block index"),
+    (FLAG_CANONICAL, "canonical"),
+    (FLAG_WIDE_SYMBOLS, "wide symbols"),
+    (FLAG_ORIGINAL_SIZE, "original size"),
+    (FLAG_FILE_INFO, "file info"),
+];
+
+/// What can be found out about a stream without decoding its data
+#[derive(Debug)]
+pub struct StreamInfo {
+    pub header: Header,
+    /// Size of the whole stream in bytes
+    pub compressed_size: u64,
+    /// Number of blocks and how many of them have their own code lengths, None without a block index
+    pub blocks: Option<(usize, usize)>,
+    /// (symbol, code) of each symbol of the first block, ordered by symbol
+    pub codes: Vec<(u16, String)>,
+}
+
+/// Reads the header, the tree of the first block and the block index of `input`,
+/// the data of the blocks is skipped
+pub fn read_info<R: Read + Seek>(mut input: R) -> Result<StreamInfo> {
+    /// The trees are at most 256 levels deep, see `construct_tree`
+    fn add_codes(node: &Node, path: &mut String, codes: &mut Vec<(u16, String)>) {
+        match node {
+            Node::Branch(_, l, r) => {
+                path.push('0');
+                add_codes(l, path, codes);
+                path.pop();
+                path.push('1');
+                add_codes(r, path, codes);
+                path.pop();
+            }
+            // a single leaf has the code 0
+            Node::Leaf(_, symbol) if path.is_empty() => codes.push((*symbol, "0".to_owned())),
+            Node::Leaf(_, symbol) => codes.push((*symbol, path.clone())),
+        }
+    }
+
+    let stream_start = input.stream_position()?;
+    let decoder = Decoder::new(&mut input)?;
+    let header = decoder.header().clone();
+
+    let mut codes = Vec::new();
+    add_codes(decoder.tree(), &mut String::new(), &mut codes);
+    codes.sort();
+    // the decoder reads ahead, so the positions below are relative to the start of the stream
+    drop(decoder);
+
+    let blocks = if header.has_flag(FLAG_BLOCK_INDEX) {
+        input.seek(SeekFrom::Start(stream_start))?;
+        Header::read(&mut input)?;
+        let blocks_start = input.stream_position()?;
+
+        // the first bit of each block tells if it has its own code lengths
+        let (index, _) = read_index_at(&mut input, blocks_start)?;
+        let mut tables = 0;
+        for &(start, _) in &index {
+            let mut byte = [0];
+            input.seek(SeekFrom::Start(blocks_start + start as u64 / 8))?;
+            input.read_exact(&mut byte)?;
+            if byte[0] & (0x80 >> (start % 8)) != 0 {
+                tables += 1;
+            }
+        }
+        Some((index.len(), tables))
+    } else {
+        None
+    };
+
+    Ok(StreamInfo {
+        header,
+        compressed_size: input.seek(SeekFrom::End(0))? - stream_start,
+        blocks,
+        codes,
+    })
+}
+
+/// Quotes and escapes `s` for JSON
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(quoted, "\\u{:04x}", c as u32).unwrap(),
+            c => quoted.push(c),
+        }
+    }
+
+    quoted.push('"');
+    quoted
+}
+
+/// `value` for JSON, null if it's None
+fn json_option<T: ToString>(value: Option<T>) -> String {
+    value.map_or("null".to_owned(), |value| value.to_string())
+}
+
+/// How a symbol is shown in the code table
+fn symbol_name(symbol: u16, eof: u16) -> String {
+    match symbol {
+        s if s == eof => "EOF".to_owned(),
+        s if (0x21..0x7f).contains(&s) => format!("'{}'", s as u8 as char),
+        s => format!("{:#04x}", s),
+    }
+}
+
+impl StreamInfo {
+    /// Names of the flags that are set
+    pub fn flag_names(&self) -> Vec<&'static str> {
+        FLAG_NAMES.iter().filter(|(flag, _)| self.header.has_flag(*flag)).map(|&(_, name)| name).collect()
+    }
+
+    /// Compressed size in percent of the original size, if it's known
+    pub fn ratio(&self) -> Option<f64> {
+        self.header.original_size.filter(|&size| size > 0).map(|size| self.compressed_size as f64 / size as f64 * 100.0)
+    }
+
+    /// The symbol that ends the data, the 0.1 format uses a byte for it
+    pub fn eof_symbol(&self) -> u16 {
+        if self.header.is_legacy() {
+            LEGACY_EOF
+        } else {
+            EOF_SYMBOL
+        }
+    }
+
+    /// Number of distinct bytes that have a code, without the EOF symbol
+    pub fn byte_symbols(&self) -> usize {
+        let eof = self.eof_symbol();
+        self.codes.iter().filter(|&&(symbol, _)| symbol != eof).count()
+    }
+
+    /// Shortest and longest code, and the mean length of all codes including EOF.
+    /// The mean is over the codes, not weighted by how often each symbol occurs.
+    pub fn code_lengths(&self) -> (usize, usize, f64) {
+        let lengths = self.codes.iter().map(|(_, code)| code.len());
+        let sum: usize = lengths.clone().sum();
+
+        (
+            lengths.clone().min().unwrap_or(0),
+            lengths.max().unwrap_or(0),
+            sum as f64 / std::cmp::max(self.codes.len(), 1) as f64,
+        )
+    }
+
+    pub fn to_text(&self) -> String {
+        let header = &self.header;
+        let file_info = header.file_info.clone().unwrap_or_default();
+        let (min_len, max_len, mean_len) = self.code_lengths();
+        let mut text = String::new();
+
+        // writing to a String can't fail
+        let mut line = |name: &str, value: String| writeln!(text, "{:<18}{}", name, value).unwrap();
+
+        line("format version:", format!("{}{}", header.version, if header.is_legacy() { " (0.1)" } else { "" }));
+        line("flags:", self.flag_names().join(", "));
+        if !header.is_legacy() {
+            line("max code length:", header.max_code_len.to_string());
+        }
+        if let Some(name) = &file_info.name {
+            line("original name:", name.clone());
+        }
+        if let Some(mtime) = file_info.mtime {
+            line("modified:", format!("{} (seconds since 1970)", mtime));
+        }
+        if let Some(mode) = file_info.mode {
+            line("permissions:", format!("{:04o}", mode));
+        }
+        line("original size:", header.original_size.map_or("unknown".to_owned(), |size| size.to_string()));
+        line("compressed size:", self.compressed_size.to_string());
+        line("ratio:", self.ratio().map_or("unknown".to_owned(), |ratio| format!("{:.1}%", ratio)));
+        if let Some((blocks, tables)) = self.blocks {
+            line("blocks:", format!("{} ({} with own code lengths)", blocks, tables));
+        }
+        line("byte symbols:", self.byte_symbols().to_string());
+        line("codes incl. EOF:", self.codes.len().to_string());
+        line("code length:", format!("min {}, max {}, mean over codes {:.2}", min_len, max_len, mean_len));
+
+        let eof = self.eof_symbol();
+        writeln!(text, "\ncodes of the first block:\n  symbol  length  code").unwrap();
+        for (symbol, code) in &self.codes {
+            writeln!(text, "  {:<8}{:<8}{}", symbol_name(*symbol, eof), code.len(), code).unwrap();
+        }
+
+        text
+    }
+
+    /// All fields as one JSON object on a single line, unknown values are null
+    pub fn to_json(&self) -> String {
+        let header = &self.header;
+        let file_info = header.file_info.clone().unwrap_or_default();
+        let (min_len, max_len, mean_len) = self.code_lengths();
+
+        let flags: Vec<String> = self.flag_names().iter().map(|name| json_string(name)).collect();
+        let codes: Vec<String> = self
+            .codes
+            .iter()
+            .map(|(symbol, code)| format!("{{\"symbol\":{},\"length\":{},\"code\":\"{}\"}}", symbol, code.len(), code))
+            .collect();
+
+        format!(
+            "{{\"version\":{},\"flags\":[{}],\"max_code_len\":{},\"name\":{},\"mtime\":{},\"mode\":{},\
+             \"original_size\":{},\"compressed_size\":{},\"ratio\":{},\"blocks\":{},\"tables\":{},\
+             \"byte_symbols\":{},\"codes_incl_eof\":{},\"eof_symbol\":{},\"min_code_len\":{},\"max_code_len_used\":{},\
+             \"mean_code_len_unweighted\":{:.4},\"codes\":[{}]}}",
+            header.version,
+            flags.join(","),
+            json_option(Some(header.max_code_len).filter(|_| !header.is_legacy())),
+            json_option(file_info.name.as_deref().map(json_string)),
+            json_option(file_info.mtime),
+            json_option(file_info.mode),
+            json_option(header.original_size),
+            self.compressed_size,
+            json_option(self.ratio().map(|ratio| format!("{:.4}", ratio))),
+            json_option(self.blocks.map(|(blocks, _)| blocks)),
+            json_option(self.blocks.map(|(_, tables)| tables)),
+            self.byte_symbols(),
+            self.codes.len(),
+            self.eof_symbol(),
+            min_len,
+            max_len,
+            mean_len,
+            codes.join(",")
+        )
+    }
+}
+
+#[test]
+fn stream_info() -> Result<()> {
+    let data = std::fs::read("./test.txt")?;
+    let compressed = crate::compress(&data);
+    let info = read_info(std::io::Cursor::new(&compressed))?;
+
+    let mut distinct = data.clone();
+    distinct.sort_unstable();
+    distinct.dedup();
+
+    assert_eq!(info.header.original_size, Some(data.len() as u64));
+    assert_eq!(info.compressed_size, compressed.len() as u64);
+    assert_eq!(info.byte_symbols(), distinct.len());
+    assert_eq!(info.codes.len(), distinct.len() + 1);
+    assert_eq!(info.blocks, Some((1, 1)));
+    assert!(info.code_lengths().1 <= crate::DEFAULT_MAX_CODE_LEN as usize);
+    assert!(info.to_json().contains(&format!("\"byte_symbols\":{},", distinct.len())));
+
+    // no code is the start of another one
+    for (_, a) in &info.codes {
+        assert_eq!(info.codes.iter().filter(|(_, b)| b.starts_with(a.as_str())).count(), 1);
+    }
+
+    let legacy = read_info(std::fs::File::open("./test.txt.huff")?)?;
+    assert!(legacy.header.is_legacy());
+    assert_eq!(legacy.blocks, None);
+    assert_eq!(legacy.byte_symbols(), legacy.codes.len() - 1);
+    assert!(legacy.to_json().contains("\"original_size\":null"));
+
+    assert_eq!(json_string("a\"b\\\n"), "\"a\\\"b\\\\\\n\"");
+
+    Ok(())
+}
@@ -0,0 +1,54 @@
+pub mod binary_io;
+
+pub mod error;
+pub use error::HuffmanError;
+
+pub mod frequency;
+pub use frequency::FrequencyTable;
+
+pub mod encode;
+pub use encode::{build_limited_tree, build_tree, compress, encode_file, Encoder, HuffmanWriter};
+
+pub mod canonical;
+
+pub mod checksum;
+
+pub mod header;
+
+pub mod table;
+
+mod pool;
+
+pub mod decode;
+pub use decode::{decode_file, decompress, Decoder, HuffmanReader};
+
+pub mod info;
+pub use info::{read_info, StreamInfo};
+
+#[derive(Debug)]
+pub enum Node {
+    Branch(usize, Box<Node>, Box<Node>),
+    Leaf(usize, u16)
+}
+
+impl Node {
+    pub fn count(&self) -> usize {
+        match self {
+            Node::Branch(count, _, _) => *count,
+            Node::Leaf(count, _) => *count
+        }
+    }
+}
+
+/// Symbol that marks the end of a block, it's outside of the byte range
+/// so every byte value can be encoded.
+pub const EOF_SYMBOL : u16 = 256;
+/// All bytes plus the EOF_SYMBOL
+pub const SYMBOL_COUNT : usize = 257;
+
+pub const MAX_BUF_SIZE : usize = 1024 * 128;
+/// Default limit for the length of a code
+pub const DEFAULT_MAX_CODE_LEN : u8 = 15;
+/// Default number of bytes per block for the HuffmanWriter
+pub const BLOCK_SIZE : usize = 1024 * 1024;
+pub const MAX_WORKERS : usize = 8;
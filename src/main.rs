@@ -1,95 +1,414 @@
-#![feature(box_syntax)]
-
 use std::env;
-use std::io::{self};
-use std::path::PathBuf;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Write};
+use std::process;
 
-mod binary_io;
+use huffman::error::{HuffmanError, Result};
+use huffman::header::{FileInfo, Header};
+use huffman::decode::{decode_parallel, decode_seekable};
+use huffman::{decode_file, encode_file, read_info, Decoder, HuffmanWriter, MAX_WORKERS};
 
-mod encode;
-use encode::encode;
+mod cli;
+use cli::{Args, Command, Input, Job, Mode, Output};
 
-mod decode;
-use decode::decode;
+/// Bytes read and written for one input
+struct Summary {
+    read: u64,
+    written: u64,
+    removed_input: bool,
+}
 
-#[derive(Debug)]
-enum MainMode {
-    Encoding,
-    Decoding
+/// Counts the bytes that are read or written through it
+struct Counter<T> {
+    inner: T,
+    count: u64,
 }
 
+impl<T> Counter<T> {
+    fn new(inner: T) -> Self {
+        Counter { inner, count: 0 }
+    }
+}
 
-fn main() -> io::Result<()> {
-    let args : Vec<String> = env::args().skip(1).collect();
+impl<R: Read> Read for Counter<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.count += len as u64;
+        Ok(len)
+    }
+}
 
-    let (mode, file_path) : (MainMode, String) = if let Some(e) = args.iter().find(|a| a == &"--encode" || a == &"-e") {
-        match args.iter().find(|a| a != &e) {
-            Some(path) => {(MainMode::Encoding, path.to_owned())},
-            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "No path specified"))
-        }
-        
-    } 
+impl<W: Write> Write for Counter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.count += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
 
-    else if let Some(e) = args.iter().find(|a| a == &"--decode" || a == &"-d") {
-        match args.iter().find(|a| a != &e) {
-            Some(path) => {(MainMode::Decoding, path.to_owned())},
-            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "No path specified"))
+fn main() {
+    let args = match cli::parse(env::args_os().skip(1)) {
+        Ok(Command::Run(args)) => args,
+        Ok(Command::Help) => {
+            // writeln! instead of println!, so `huffman --help | head` doesn't panic
+            let _ = writeln!(io::stdout(), "{}", cli::USAGE);
+            return;
         }
-    } else {
-        eprintln!("You didn't specify wheather to decode or encode the data. Guessing based on file ending");
-        match args.iter().next() {
-            Some(path) => {
-                if path.ends_with(".huff") {
-                    (MainMode::Decoding, path.to_owned())
+        Err(msg) => {
+            eprintln!("huffman: {}\n\n{}", msg, cli::USAGE);
+            process::exit(2);
+        }
+    };
+
+    if args.mode.is_none() {
+        eprintln!("huffman: no command given, guessing whether to compress or decompress from the .huff ending");
+    }
+
+    let mut failed = 0;
+
+    // stdout may be the output, so everything else goes to stderr
+    for input in cli::expand_inputs(&args.inputs, args.mode, args.recursive) {
+        let input = match input {
+            Ok(input) => input,
+            Err((path, msg)) => {
+                eprintln!("huffman: {}: {}", path.display(), msg);
+                failed += 1;
+                continue;
+            }
+        };
+
+        let name = match &input {
+            Input::File(path) => path.display().to_string(),
+            Input::Stdin => "stdin".to_owned(),
+        };
+
+        let mut job = match args.job(input) {
+            Ok(job) => job,
+            Err(msg) => {
+                eprintln!("huffman: {}: {}", name, msg);
+                failed += 1;
+                continue;
+            }
+        };
+
+        if job.mode == Mode::Info {
+            match show_info(&job.input, &name, args.json) {
+                // `huffman info *.huff | head -1` closed stdout, the other files aren't wanted either
+                Err(HuffmanError::Io(e)) if e.kind() == io::ErrorKind::BrokenPipe => break,
+                Err(e) => {
+                    eprintln!("huffman: {}: {}", name, e);
+                    failed += 1;
                 }
-                else {
-                    (MainMode::Encoding, path.to_owned())
+                Ok(()) => {}
+            }
+            continue;
+        }
+
+        let start = std::time::Instant::now();
+
+        let result = run(&mut job, &args);
+
+        match result {
+            // the results of test go to stdout, nothing else is written there
+            _ if job.mode == Mode::Test => {
+                let line = match &result {
+                    Ok(_) => format!("{}: OK", name),
+                    Err(e) => {
+                        failed += 1;
+                        format!("{}: FAILED ({})", name, e)
+                    }
+                };
+
+                // writeln! instead of println!, `huffman test *.huff | head -1` closes stdout after the first line
+                match writeln!(io::stdout(), "{}", line) {
+                    Err(e) if e.kind() == io::ErrorKind::BrokenPipe => break,
+                    Err(e) => {
+                        eprintln!("huffman: stdout: {}", e);
+                        failed += 1;
+                    }
+                    Ok(()) => {}
                 }
-                },
-            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "No path specified"))
+            }
+            Ok(summary) => {
+                let result = match (&job.output, summary.removed_input) {
+                    (Output::Stdout, _) => "written to stdout".to_owned(),
+                    (Output::Null, _) => "discarded".to_owned(),
+                    (Output::File(path), true) => format!("replaced with {}", path.display()),
+                    (Output::File(path), false) => format!("created {}", path.display()),
+                };
+
+                eprintln!(
+                    "{}: {} -> {} bytes ({:.1}%) in {:.2}s, {}",
+                    name,
+                    summary.read,
+                    summary.written,
+                    summary.written as f64 / std::cmp::max(summary.read, 1) as f64 * 100.0,
+                    start.elapsed().as_secs_f64(),
+                    result
+                );
+            }
+            Err(e) => {
+                eprintln!("huffman: {}: {}", name, e);
+                failed += 1;
+            }
         }
-    };
+    }
 
-    let path = PathBuf::from(file_path);
+    if failed > 0 {
+        eprintln!("huffman: {} input(s) failed", failed);
+        process::exit(1);
+    }
+}
 
-    if ! path.exists() {
-        return Err(io::Error::new(io::ErrorKind::NotFound, "File doesn't exist."))
+/// The output of `job` gets the name stored in the header when decompressing, if it wasn't given with -o
+fn run(job: &mut Job, args: &Args) -> Result<Summary> {
+    // a missing input shouldn't create (or truncate) the output
+    if let Input::File(path) = &job.input {
+        if !fs::metadata(path)?.is_file() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Not a regular file").into());
+        }
     }
 
-    println!("{:?} {:?}", mode, &path);
+    // name, time and permissions of the original file, when decompressing they are in the header
+    let info = match (job.mode, &job.input) {
+        _ if args.no_name => None,
+        (Mode::Compress, Input::File(path)) => Some(FileInfo::from_path(path)?),
+        (Mode::Decompress, Input::File(path)) => Header::read(&mut File::open(path)?)?.file_info,
+        (Mode::Test, _) | (Mode::Info, _) | (_, Input::Stdin) => None,
+    };
 
-    let start = std::time::Instant::now();
+    if job.mode == Mode::Decompress && args.output.is_none() {
+        if let (Output::File(path), Some(name)) = (&mut job.output, info.as_ref().and_then(|info| info.name.as_ref())) {
+            // only the name is stored, the directory stays the same
+            path.set_file_name(name);
+        }
+    }
+
+    let (read, written) = match &job.output {
+        Output::Stdout => process(job, BufWriter::new(io::stdout()), info.as_ref())?,
+        Output::Null => process(job, io::sink(), None)?,
+        Output::File(path) => {
+            if job.input == Input::File(path.clone()) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "Input and output are the same file").into());
+            }
+
+            let file = if args.force {
+                File::create(path)
+            } else {
+                OpenOptions::new().write(true).create_new(true).open(path)
+            };
+            let file = file.map_err(|e| match e.kind() {
+                io::ErrorKind::AlreadyExists => {
+                    io::Error::new(e.kind(), format!("{} already exists, use -f to overwrite it", path.display()))
+                }
+                _ => e,
+            })?;
 
-    match mode {
-        MainMode::Encoding => {
-            encode(path)?
-        },
-        MainMode::Decoding => {
-            decode(path)?
+            let result = process(job, BufWriter::new(file), info.as_ref()).and_then(|counts| {
+                if let (Mode::Decompress, Some(info)) = (job.mode, &info) {
+                    info.restore(path)?;
+                }
+                Ok(counts)
+            });
+
+            if result.is_err() {
+                // don't leave a half written file behind
+                let _ = fs::remove_file(path);
+            }
+
+            result?
+        }
+    };
+
+    // like gzip, the input is replaced by the output
+    let removed_input = match (&job.input, &job.output) {
+        (Input::File(path), Output::File(_)) if !args.keep => {
+            fs::remove_file(path)?;
+            true
+        }
+        _ => false,
+    };
+
+    Ok(Summary {
+        read,
+        written,
+        removed_input,
+    })
+}
+
+/// Reads the whole input into memory
+fn read_input(input: &Input) -> Result<Vec<u8>> {
+    match input {
+        Input::File(path) => Ok(fs::read(path)?),
+        Input::Stdin => {
+            let mut data = Vec::new();
+            io::stdin().lock().read_to_end(&mut data)?;
+            Ok(data)
         }
     }
+}
+
+/// Prints the header, sizes and codes of `input` to stdout
+fn show_info(input: &Input, name: &str, json: bool) -> Result<()> {
+    let info = match input {
+        Input::File(path) => read_info(File::open(path)?)?,
+        // stdin can't seek to the block index at the end
+        Input::Stdin => read_info(io::Cursor::new(read_input(input)?))?,
+    };
+
+    // writeln! instead of println!, so `huffman info a.huff | head` doesn't panic
+    let mut stdout = io::stdout();
+    if json {
+        writeln!(stdout, "{}", info.to_json())?;
+    } else {
+        writeln!(stdout, "{}:\n{}", name, info.to_text())?;
+    }
 
-    let dur = start.elapsed();
-    println!("Finished. Took {}s {}ms", dur.as_secs(), dur.subsec_millis());
     Ok(())
 }
 
+/// Returns the number of bytes read and written, `info` gets stored when compressing a file
+fn process<W: Write + Send>(job: &Job, output: W, info: Option<&FileInfo>) -> Result<(u64, u64)> {
+    let mut output = Counter::new(output);
 
-#[derive(Debug)]
-pub enum Node {
-    Branch(usize, Box<Node>, Box<Node>),
-    Leaf(usize, u8)
-}
+    let read = match (job.mode, &job.input) {
+        (Mode::Compress, Input::File(path)) => encode_file(path, &mut output, info.cloned())?.read_bytes as u64,
+        (Mode::Compress, Input::Stdin) => {
+            // stdin can only be read once, so each block gets the tree of its own bytes
+            let mut writer = HuffmanWriter::new(&mut output)?;
+            let read = io::copy(&mut io::stdin().lock(), &mut writer)?;
+            writer.finish()?;
+            read
+        }
+        (Mode::Decompress, Input::File(path)) => {
+            decode_file(path, &mut output)?;
+            fs::metadata(path)?.len()
+        }
+        (Mode::Decompress, Input::Stdin) => {
+            let mut input = Counter::new(io::stdin().lock());
+            Decoder::new(&mut input)?.decode(&mut output)?;
+            input.count
+        }
+        (Mode::Test, Input::File(path)) => {
+            decode_seekable(File::open(path)?, &mut output, MAX_WORKERS)?;
+            fs::metadata(path)?.len()
+        }
+        (Mode::Test, Input::Stdin) => {
+            // the block index is at the end, so all of stdin is needed
+            let data = read_input(&Input::Stdin)?;
 
-impl Node {
-    pub fn count(&self) -> usize {
-        match self {
-            Node::Branch(count, _, _) => *count,
-            Node::Leaf(count, _) => *count
+            decode_parallel(&data, &mut output, MAX_WORKERS)?;
+            data.len() as u64
         }
+        (Mode::Info, _) => unreachable!("info is shown by show_info"),
+    };
+
+    output.flush()?;
+    Ok((read, output.count))
+}
+
+#[test]
+fn file_info_restored() -> Result<()> {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    /// Runs the job of the only input like main does
+    fn run_args(args: &[&std::path::Path]) -> Result<Summary> {
+        let args = match cli::parse(args.iter().map(|arg| arg.as_os_str().to_owned())) {
+            Ok(Command::Run(args)) => args,
+            _ => panic!("Arguments are not valid"),
+        };
+        let mut job = args.job(args.inputs[0].clone()).expect("Job is not valid");
+        run(&mut job, &args)
+    }
+
+    let dir = env::temp_dir().join(format!("huffman_file_info_{}", process::id()));
+    fs::create_dir_all(&dir)?;
+    let original = dir.join("original.txt");
+    fs::write(&original, b"name, time and permissions")?;
+
+    let mtime = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+    OpenOptions::new().write(true).open(&original)?.set_modified(mtime)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&original, fs::Permissions::from_mode(0o640))?;
     }
+
+    run_args(&["compress".as_ref(), &original])?;
+    assert!(!original.exists());
+
+    // the output gets the stored name, not the one of the archive
+    let archive = dir.join("renamed.txt.huff");
+    fs::rename(dir.join("original.txt.huff"), &archive)?;
+    run_args(&["decompress".as_ref(), &archive])?;
+    assert!(!dir.join("renamed.txt").exists());
+
+    assert_eq!(fs::read(&original)?, b"name, time and permissions");
+    let metadata = fs::metadata(&original)?;
+    assert_eq!(metadata.modified()?, mtime);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o640);
+    }
+
+    fs::remove_dir_all(&dir)?;
+    Ok(())
 }
 
-pub const MAX_BUF_SIZE : usize = 1024 * 128;
-pub const MAX_WORKERS : usize = 8;
-pub const HEADER : &[u8] = b"HUFFMAN 0.1 Matthias Kind";
\ No newline at end of file
+#[test]
+fn run_files() -> Result<()> {
+    /// Runs the job of the only input like main does
+    fn run_args(args: &[&std::path::Path]) -> Result<Summary> {
+        let args = match cli::parse(args.iter().map(|arg| arg.as_os_str().to_owned())) {
+            Ok(Command::Run(args)) => args,
+            _ => panic!("Arguments are not valid"),
+        };
+        let mut job = args.job(args.inputs[0].clone()).expect("Job is not valid");
+        run(&mut job, &args)
+    }
+    let arg = |s: &'static str| std::path::Path::new(s);
+
+    let dir = env::temp_dir().join(format!("huffman_run_{}", process::id()));
+    fs::create_dir_all(&dir)?;
+    let input = dir.join("a.txt");
+    let archive = dir.join("a.txt.huff");
+    fs::write(&input, b"compressed and decompressed")?;
+
+    // with -k the input stays
+    assert!(!run_args(&[arg("compress"), arg("-k"), &input])?.removed_input);
+    assert!(input.exists() && archive.exists());
+
+    // an existing output is only overwritten with -f
+    fs::write(&archive, b"old")?;
+    assert!(run_args(&[arg("compress"), &input]).is_err());
+    assert_eq!(fs::read(&archive)?, b"old");
+    assert!(input.exists());
+
+    // without -k the input is removed
+    assert!(run_args(&[arg("compress"), arg("-f"), &input])?.removed_input);
+    assert!(!input.exists());
+
+    // a failed decompression doesn't leave a part of the output, and keeps the input
+    let compressed = fs::read(&archive)?;
+    fs::write(&archive, &compressed[..compressed.len() / 2])?;
+    assert!(run_args(&[arg("decompress"), &archive]).is_err());
+    assert!(!input.exists());
+    assert!(archive.exists());
+
+    fs::write(&archive, &compressed)?;
+    run_args(&[arg("decompress"), &archive])?;
+    assert_eq!(fs::read(&input)?, b"compressed and decompressed");
+    assert!(!archive.exists());
+
+    // the input can't be its own output
+    assert!(run_args(&[arg("compress"), arg("-o"), &input, &input]).is_err());
+    assert_eq!(fs::read(&input)?, b"compressed and decompressed");
+
+    fs::remove_dir_all(&dir)?;
+    Ok(())
+}
@@ -0,0 +1,121 @@
+use crate::error::{HuffmanError, Result};
+use std::collections::BTreeMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc::channel, Mutex};
+use std::thread;
+
+/// Runs `work` for each of `jobs` on up to `thread_count` threads and passes the results to `sink` in the order of the jobs.
+/// The jobs are taken and the results are passed on by the calling thread. At most two jobs per thread are taken
+/// before their results are written, so only a few blocks are in memory at once.
+/// Stops at the first error of `jobs`, `work` or `sink` and returns it.
+pub(crate) fn ordered<J, T, F, S>(thread_count: usize, mut jobs: impl Iterator<Item = Result<J>>, work: F, mut sink: S) -> Result<()>
+where
+    J: Send,
+    T: Send,
+    F: Fn(J) -> Result<T> + Sync,
+    S: FnMut(T) -> Result<()>,
+{
+    let thread_count = std::cmp::max(thread_count, 1);
+    let (job_sender, job_receiver) = channel::<(usize, J)>();
+    let (result_sender, result_receiver) = channel::<(usize, Result<T>)>();
+    let job_receiver = Mutex::new(job_receiver);
+
+    thread::scope(|s| {
+        let mut workers = Vec::with_capacity(thread_count);
+
+        let spawned: std::io::Result<()> = (0..thread_count).try_for_each(|t_id| {
+            let (job_receiver, result_sender, work) = (&job_receiver, result_sender.clone(), &work);
+
+            let worker = thread::Builder::new().name(format!("worker_{}", t_id)).spawn_scoped(s, move || loop {
+                // the jobs end when all of them are taken or the caller stopped because of an error
+                let (id, job) = match job_receiver.lock().map(|jobs| jobs.recv()) {
+                    Ok(Ok(job)) => job,
+                    _ => break,
+                };
+
+                // a panic becomes the result of the job, otherwise the caller would wait for it forever
+                let result = panic::catch_unwind(AssertUnwindSafe(|| work(job)))
+                    .unwrap_or(Err(HuffmanError::Internal("Worker thread panicked")));
+                if result_sender.send((id, result)).is_err() {
+                    break;
+                }
+            })?;
+
+            workers.push(worker);
+            Ok(())
+        });
+
+        // only the workers may hold a sender, so recv fails once they are all finished
+        drop(result_sender);
+
+        let result = spawned.map_err(HuffmanError::from).and_then(|()| {
+            // results that arrived before the ones in front of them
+            let mut pending: BTreeMap<usize, T> = BTreeMap::new();
+            let (mut taken, mut finished, mut next_expected) = (0, 0, 0);
+            let mut jobs_left = true;
+
+            loop {
+                while jobs_left && taken - finished < 2 * thread_count {
+                    match jobs.next() {
+                        Some(job) => {
+                            job_sender.send((taken, job?)).map_err(|_| HuffmanError::Internal("Worker threads stopped"))?;
+                            taken += 1;
+                        }
+                        None => jobs_left = false,
+                    }
+                }
+
+                if finished == taken {
+                    return Ok(());
+                }
+
+                let (id, result) = result_receiver.recv().map_err(|_| HuffmanError::Internal("Worker threads stopped"))?;
+                finished += 1;
+                pending.insert(id, result?);
+
+                while let Some(result) = pending.remove(&next_expected) {
+                    sink(result)?;
+                    next_expected += 1;
+                }
+            }
+        });
+
+        // the workers stop once no more jobs can arrive
+        drop(job_sender);
+
+        let mut worker_panicked = false;
+        for worker in workers {
+            worker_panicked |= worker.join().is_err();
+        }
+
+        if worker_panicked {
+            return Err(HuffmanError::Internal("Worker thread panicked"));
+        }
+        result
+    })
+}
+
+#[test]
+fn ordered_results() -> Result<()> {
+    let mut results = Vec::new();
+    let jobs = (0..100u64).map(Ok);
+
+    // later jobs finish first
+    ordered(4, jobs, |n| {
+        thread::sleep(std::time::Duration::from_micros(100 - n));
+        Ok(n * 2)
+    }, |n| {
+        results.push(n);
+        Ok(())
+    })?;
+    assert_eq!(results, (0..100).map(|n| n * 2).collect::<Vec<u64>>());
+
+    // a panic is an error and doesn't stop the caller
+    let result = ordered(2, (0..10).map(Ok), |n: u8| if n == 5 { panic!("job {}", n) } else { Ok(n) }, |_| Ok(()));
+    assert!(matches!(result, Err(HuffmanError::Internal(_))));
+
+    let result = ordered(2, (0..10).map(|n| if n == 7 { Err(HuffmanError::UnexpectedEof) } else { Ok(n) }), Ok, |_| Ok(()));
+    assert!(matches!(result, Err(HuffmanError::UnexpectedEof)));
+
+    Ok(())
+}
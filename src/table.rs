@@ -0,0 +1,324 @@
+use crate::binary_io::{BinaryReader, BitBuf, MAX_READ_BITS};
+use crate::{Node, EOF_SYMBOL};
+use std::io::{self, Read};
+
+/// Bits looked up at once in the first table
+const PRIMARY_BITS: u8 = 10;
+/// Bits looked up at once in the tables for longer codes
+const SECONDARY_BITS: u8 = 6;
+/// Bits looked up at once in the table of byte sequences
+const FAST_BITS: u8 = 12;
+/// Most bytes an entry of the fast table can hold
+const FAST_BYTES: u32 = 3;
+
+#[derive(Clone, Copy, Debug)]
+enum Entry {
+    /// symbol and the length of its code in this table
+    Symbol(u16, u8),
+    /// the code is longer, continue with the next bits in the table at this index
+    Table(u32),
+}
+
+#[derive(Debug)]
+struct Table {
+    bits: u8,
+    entries: Vec<Entry>,
+}
+
+/// Lookup tables to decode a whole symbol with one peek instead of walking the tree bit by bit.
+/// The first table is indexed by the next PRIMARY_BITS bits, codes that are longer
+/// continue in smaller tables for the bits after that.
+/// For decode_bytes there is another table indexed by the next FAST_BITS bits that gives all bytes
+/// whose codes fit in them (up to FAST_BYTES), so short codes are decoded a few at a time.
+#[derive(Debug)]
+pub struct DecodeTable {
+    tables: Vec<Table>,
+    /// the number of bits the bytes take in the lowest 6 bits (so it can be used as the shift directly),
+    /// then how many bytes there are (2 bits) and the bytes.
+    /// 0 bytes if the first code is longer than FAST_BITS or not a byte
+    fast: Box<[u32; 1 << FAST_BITS]>,
+    /// length of the longest code
+    max_len: u8,
+}
+
+/// Number of bits of the longest path below `node`
+fn height(node: &Node) -> u8 {
+    match node {
+        Node::Branch(_, l, r) => 1 + std::cmp::max(height(l), height(r)),
+        Node::Leaf(_, _) => 0,
+    }
+}
+
+impl DecodeTable {
+    pub fn new(root: &Node) -> Self {
+        DecodeTable::with_eof(root, EOF_SYMBOL)
+    }
+
+    /// Like new, but the code of `eof` is decoded as EOF_SYMBOL.
+    /// In the 0.1 format a byte marks the end, this way it stops decode_bytes as well.
+    pub fn with_eof(root: &Node, eof: u16) -> Self {
+        let mut table = DecodeTable {
+            tables: Vec::new(),
+            fast: Box::new([0; 1 << FAST_BITS]),
+            max_len: std::cmp::max(height(root), 1),
+        };
+
+        // a tree of one leaf has the 1 bit code 0, a 1 bit gets the symbol as well
+        if let Node::Leaf(_, symbol) = root {
+            table.tables.push(Table {
+                bits: 1,
+                entries: vec![Entry::Symbol(map_eof(*symbol, eof), 1); 2],
+            });
+        } else {
+            table.add_table(root, PRIMARY_BITS, eof);
+        }
+
+        let fast = Box::new(std::array::from_fn(|pattern| table.fast_entry(pattern)));
+        table.fast = fast;
+        table
+    }
+
+    /// The entry of the fast table for the next FAST_BITS bits `pattern`
+    fn fast_entry(&self, pattern: usize) -> u32 {
+        let primary = &self.tables[0];
+        let (mut entry, mut used, mut count) = (0, 0, 0);
+
+        while count < FAST_BYTES {
+            let left = FAST_BITS - used;
+            let rest = pattern & ((1 << left) - 1);
+            // the bits of the primary table, after the end of pattern they are 0
+            let index = if left >= primary.bits {
+                rest >> (left - primary.bits)
+            } else {
+                rest << (primary.bits - left)
+            };
+
+            match primary.entries[index] {
+                Entry::Symbol(symbol, len) if len <= left && symbol <= u8::MAX as u16 => {
+                    entry |= (symbol as u32) << (8 * count + 8);
+                    used += len;
+                    count += 1;
+                }
+                _ => break,
+            }
+        }
+
+        entry | used as u32 | count << 6
+    }
+
+    /// Adds the table for all codes below `node` and returns its index.
+    fn add_table(&mut self, node: &Node, max_bits: u8, eof: u16) -> usize {
+        let bits = std::cmp::min(max_bits, height(node));
+        let index = self.tables.len();
+
+        self.tables.push(Table {
+            bits,
+            entries: Vec::new(),
+        });
+
+        let mut entries = Vec::with_capacity(1 << bits);
+
+        for pattern in 0..(1usize << bits) {
+            // walk the tree with the bits of pattern, highest bit first
+            let mut current = node;
+            let mut len = 0;
+
+            while let Node::Branch(_, l, r) = current {
+                if len == bits {
+                    break;
+                }
+
+                let go_right = (pattern >> (bits - len - 1)) & 1 == 1;
+                current = if go_right { r } else { l };
+                len += 1;
+            }
+
+            let entry = match current {
+                Node::Leaf(_, symbol) => Entry::Symbol(map_eof(*symbol, eof), len),
+                // all bits were used, every pattern ends at a different branch
+                Node::Branch(_, _, _) => Entry::Table(self.add_table(current, SECONDARY_BITS, eof) as u32),
+            };
+
+            entries.push(entry);
+        }
+
+        self.tables[index].entries = entries;
+        index
+    }
+
+    /// Reads the next symbol
+    pub fn decode_symbol<R: Read>(&self, reader: &mut BinaryReader<R>) -> io::Result<u16> {
+        let mut table = &self.tables[0];
+
+        loop {
+            let bits = reader.peek_bits(table.bits)?;
+
+            match table.entries[bits] {
+                Entry::Symbol(symbol, len) => {
+                    reader.consume_bits(len)?;
+                    return Ok(symbol);
+                }
+                Entry::Table(i) => {
+                    reader.consume_bits(table.bits)?;
+                    table = &self.tables[i as usize];
+                }
+            }
+        }
+    }
+
+    /// Reads the next symbol from `bits`, the whole code has to be in there
+    #[inline]
+    fn decode_bits(&self, bits: &mut BitBuf) -> u16 {
+        let mut table = &self.tables[0];
+
+        loop {
+            match table.entries[bits.peek(table.bits)] {
+                Entry::Symbol(symbol, len) => {
+                    bits.consume(len);
+                    return symbol;
+                }
+                Entry::Table(i) => {
+                    bits.consume(table.bits);
+                    table = &self.tables[i as usize];
+                }
+            }
+        }
+    }
+
+    /// Decodes bytes and appends them to `buf` until `max_len` bytes were added or a symbol
+    /// that isn't a byte is read. That symbol is returned, None if `max_len` was reached.
+    /// This is the fast way to decode a lot of symbols, short codes are decoded a few at once
+    /// and the bits are only refilled once for a few lookups.
+    pub fn decode_bytes<R: Read>(&self, reader: &mut BinaryReader<R>, buf: &mut Vec<u8>, max_len: usize) -> io::Result<Option<u16>> {
+        let start = buf.len();
+        // room for the whole last entry of the fast table, it's cut off below
+        buf.resize(start + max_len + 4, 0);
+
+        let mut len = 0;
+        let result = self.decode_into(reader, &mut buf[start..], max_len, &mut len);
+
+        buf.truncate(start + len);
+        result
+    }
+
+    /// Decodes into `out`, `len` is the number of bytes in it when this returns (also for an error)
+    fn decode_into<R: Read>(&self, reader: &mut BinaryReader<R>, out: &mut [u8], max_len: usize, len: &mut usize) -> io::Result<Option<u16>> {
+        // lookups of the fast table after each refill, without checking the bits that are left in between
+        const BATCH: usize = (MAX_READ_BITS / FAST_BITS) as usize;
+        const BATCH_BITS: u8 = BATCH as u8 * FAST_BITS;
+        const BATCH_BYTES: usize = BATCH * FAST_BYTES as usize;
+        // longer codes (only without a length limit) are left to decode_symbol
+        let fits = self.max_len <= MAX_READ_BITS;
+
+        // while the bits of a batch are in the buffer and the whole entries of the fast table fit in `out`
+        while fits && *len + BATCH_BYTES <= max_len {
+            reader.refill()?;
+            if reader.bits().left < std::cmp::max(BATCH_BITS, self.max_len) {
+                break;
+            }
+
+            // the bits are kept in a local copy, that's a lot faster than going through the reader each time
+            let mut bits = reader.bits();
+            let mut stop = None;
+
+            'batches: while *len + BATCH_BYTES <= max_len {
+                // afterwards at least MAX_READ_BITS bits are there
+                if !reader.refill_buffered(&mut bits) && bits.left < BATCH_BITS {
+                    break;
+                }
+
+                for _ in 0..BATCH {
+                    let entry = self.fast[bits.peek(FAST_BITS)];
+                    let count = (entry >> 6) & 3;
+
+                    if count > 0 {
+                        // writes a 0 after the bytes as well, the next bytes overwrite it
+                        out[*len..*len + 4].copy_from_slice(&(entry >> 8).to_le_bytes());
+                        bits.consume((entry & 0x3f) as u8);
+                        *len += count as usize;
+                        continue;
+                    }
+
+                    // a long code may take the bits of the rest of the batch, so it ends the batch
+                    if bits.left < self.max_len && !reader.refill_buffered(&mut bits) {
+                        break 'batches;
+                    }
+
+                    match self.decode_bits(&mut bits) {
+                        symbol if symbol > u8::MAX as u16 => {
+                            stop = Some(symbol);
+                            break 'batches;
+                        }
+                        symbol => out[*len] = symbol as u8,
+                    }
+                    *len += 1;
+                    continue 'batches;
+                }
+            }
+
+            reader.set_bits(bits);
+            if stop.is_some() {
+                return Ok(stop);
+            }
+        }
+
+        // the last bytes, or close to the end of the source where the code may be shorter than the longest one
+        while *len < max_len {
+            match self.decode_symbol(reader)? {
+                symbol if symbol > u8::MAX as u16 => return Ok(Some(symbol)),
+                symbol => out[*len] = symbol as u8,
+            }
+            *len += 1;
+        }
+
+        Ok(None)
+    }
+}
+
+fn map_eof(symbol: u16, eof: u16) -> u16 {
+    if symbol == eof {
+        EOF_SYMBOL
+    } else {
+        symbol
+    }
+}
+
+#[test]
+fn long_codes_roundtrip() -> io::Result<()> {
+    // doubling counts give the deepest possible tree, so the codes need the secondary tables
+    // (limited to DEFAULT_MAX_CODE_LEN bits)
+    let mut data = Vec::new();
+    for byte in 0..17u8 {
+        data.extend(std::iter::repeat_n(byte, 1 << byte));
+    }
+
+    let compressed = crate::compress(&data);
+    let decoder = crate::Decoder::new(&compressed[..])?;
+    assert!(height(decoder.tree()) > PRIMARY_BITS);
+    assert!(height(decoder.tree()) <= crate::DEFAULT_MAX_CODE_LEN);
+
+    assert_eq!(crate::decompress(&compressed)?, data);
+
+    Ok(())
+}
+
+#[test]
+fn nested_tables_roundtrip() -> crate::error::Result<()> {
+    // without a length limit, doubling counts give codes longer than one secondary table can hold
+    let mut counter = [0; crate::SYMBOL_COUNT];
+    let mut data = Vec::new();
+    for byte in 0..21u8 {
+        counter[byte as usize] = 1 << byte;
+        data.extend(std::iter::repeat_n(byte, 1 << byte));
+    }
+    counter[crate::EOF_SYMBOL as usize] = 1;
+
+    let encoder = crate::Encoder::new(crate::build_tree(&counter)?)?;
+    assert!(height(encoder.tree()) > PRIMARY_BITS + SECONDARY_BITS);
+
+    let mut compressed = Vec::new();
+    encoder.encode(&data[..], &mut compressed)?;
+    assert_eq!(crate::decompress(&compressed)?, data);
+
+    Ok(())
+}